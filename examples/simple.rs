@@ -1,15 +1,16 @@
-use smtp_server::{EventHandler, SmtpServer};
+use smtp_server::{tls::Certificate, EventHandler, SmtpServer};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    struct EHandler {};
+    struct EHandler {}
 
     #[async_trait::async_trait]
     impl EventHandler for EHandler {
         async fn on_mail<'b>(
             &self,
+            _session: &smtp_server::session::Session,
             email: std::pin::Pin<&email_parser::email::Email<'b>>,
         ) -> Result<(), String> {
             log::info!("{:?}", email.as_ref().body.as_ref());
@@ -17,7 +18,28 @@ async fn main() {
         }
     }
 
-    SmtpServer::new(EHandler {}, "mubelotix.dev")
-        .tls("certificate.pfx", "password")
-        .run();
+    let mut server = SmtpServer::new(EHandler {}, "mubelotix.dev");
+    server.tls("certificate.pfx", "password")?;
+
+    // reload the certificate on SIGHUP, after it has been renewed
+    #[cfg(unix)]
+    if let Some(handle) = server.certificate_handle() {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match Certificate::from_pkcs12_file("certificate.pfx", "password")
+                    .and_then(|c| handle.reload(c))
+                {
+                    Ok(()) => log::info!("Certificate reloaded"),
+                    Err(e) => log::error!("Failed to reload the certificate: {}", e),
+                }
+            }
+        });
+    }
+
+    server.run()?;
+
+    Ok(())
 }
@@ -0,0 +1,401 @@
+use crate::commands::{LocalPart, LocalPartRules};
+use crate::error::AliasError;
+use crate::session::Address;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Maximum number of aliases followed to reach a mailbox.
+const MAX_DEPTH: usize = 20;
+
+/// The virtual domains of the server and the aliases redirecting their addresses to other addresses.
+///
+/// The table is a text file with one entry per line. Empty lines and lines starting with `#` are ignored.
+///
+/// ```text
+/// # the domains the server receives mail for
+/// domain example.com
+/// domain example.org
+///
+/// # an alias and the addresses it is delivered to
+/// postmaster@example.com: alice@example.com
+/// team@example.com: alice@example.com, bob@example.com, team-archive@example.com
+/// # an alias to itself is also delivered to its own mailbox
+/// bob@example.com: bob@example.com, bob@example.net
+/// # a regular expression matched against the whole normalized address, `$1`... are replaced by its groups
+/// /^(.+)-owner@example\.com$/: ${1}-admin@example.com
+/// # a catch-all for the addresses of a domain without another alias
+/// @example.org: alice@example.com
+/// ```
+///
+/// Exact aliases are tried first, then the alias of the address without its detail, then the regular expressions
+/// in the order of the file, then the catch-all of the domain. An alias of `alice@example.com` applies to
+/// `alice+lists@example.com`, and its addresses get the detail: `alice.smith+lists@example.com`.
+/// The addresses an alias leads to are resolved again, and an address without alias is a mailbox.
+///
+/// The local parts are compared and split according to the [rules](AliasTable::local_parts) of the table.
+#[derive(Debug, Default)]
+pub struct AliasTable {
+    domains: HashSet<String>,
+    aliases: Vec<(Address, Vec<Address>)>,
+    /// The position of the aliases in `aliases`, by normalized address.
+    index: HashMap<String, usize>,
+    patterns: Vec<(Regex, Vec<String>)>,
+    catch_alls: HashMap<String, Vec<Address>>,
+    local_parts: LocalPartRules,
+}
+
+/// An error resolving an address with an [AliasTable].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveError {
+    /// The aliases of this address lead back to it.
+    Loop(Address),
+    /// More aliases than allowed must be followed.
+    TooDeep,
+    /// A regular expression alias gives something that is not an address.
+    InvalidAddress(String),
+}
+
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::Loop(address) => write!(f, "alias loop for {}", address),
+            ResolveError::TooDeep => write!(f, "too many nested aliases"),
+            ResolveError::InvalidAddress(address) => write!(f, "invalid address {:?}", address),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl AliasTable {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<AliasTable, AliasError> {
+        let path = path.as_ref();
+        let table = std::fs::read_to_string(path).map_err(|error| AliasError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        AliasTable::parse(&table)
+    }
+
+    /// Parse a table in the format of the [files](AliasTable).
+    pub fn parse(table: &str) -> Result<AliasTable, AliasError> {
+        let mut aliases = AliasTable::default();
+
+        for (number, line) in table.lines().enumerate() {
+            let syntax_error = |message: String| AliasError::Syntax {
+                line: number + 1,
+                message,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(domain) = line.strip_prefix("domain ") {
+                aliases.domains.insert(domain.trim().to_lowercase());
+                continue;
+            }
+
+            // the colon ending a regular expression is the one after its last slash
+            let separator = match line.starts_with('/') {
+                true => line.rfind("/:").map(|p| p + 1),
+                false => line.find(':'),
+            };
+            let (key, targets) = match separator {
+                Some(p) => (
+                    line[..p].trim(),
+                    line[p + 1..]
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty()),
+                ),
+                None => return Err(syntax_error("expecting a ':' after the alias".to_string())),
+            };
+
+            if let Some(pattern) = key.strip_prefix('/').and_then(|k| k.strip_suffix('/')) {
+                let regex = Regex::new(pattern).map_err(|e| syntax_error(e.to_string()))?;
+                aliases
+                    .patterns
+                    .push((regex, targets.map(str::to_string).collect()));
+                continue;
+            }
+            let targets = targets
+                .map(|target| {
+                    Address::parse(target)
+                        .ok_or_else(|| syntax_error(format!("invalid address {:?}", target)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if targets.is_empty() {
+                return Err(syntax_error(
+                    "an alias needs at least one address".to_string(),
+                ));
+            }
+            match key.strip_prefix('@') {
+                Some(domain) => {
+                    aliases.catch_alls.insert(domain.to_lowercase(), targets);
+                }
+                None => match Address::parse(key) {
+                    Some(address) => aliases.aliases.push((address, targets)),
+                    None => return Err(syntax_error(format!("invalid alias {:?}", key))),
+                },
+            }
+        }
+        aliases.reindex();
+        Ok(aliases)
+    }
+
+    /// Compare and split the local parts with these rules.
+    pub fn local_parts(mut self, rules: LocalPartRules) -> Self {
+        self.local_parts = rules;
+        self.reindex();
+        self
+    }
+
+    /// The rules comparing and splitting the local parts.
+    pub(crate) fn rules(&self) -> &LocalPartRules {
+        &self.local_parts
+    }
+
+    fn reindex(&mut self) {
+        let keys: Vec<String> = self
+            .aliases
+            .iter()
+            .map(|(address, _)| self.key(address))
+            .collect();
+        // the last alias of an address replaces the previous ones
+        self.index = keys
+            .into_iter()
+            .enumerate()
+            .map(|(i, key)| (key, i))
+            .collect();
+    }
+
+    /// The normalized address, with the local part unquoted, to compare addresses.
+    fn key(&self, address: &Address) -> String {
+        let local_part = LocalPart::from_unquoted(&address.local_part).normalize(&self.local_parts);
+        format!("{}@{}", local_part, address.domain.to_lowercase())
+    }
+
+    /// Whether the server receives mail for this domain.
+    pub fn is_local(&self, domain: &str) -> bool {
+        self.domains.contains(&domain.to_lowercase())
+    }
+
+    /// The mailboxes an address is delivered to, which is the address itself if it has no alias.
+    pub fn resolve(&self, address: &Address) -> Result<Vec<Address>, ResolveError> {
+        let mut mailboxes = Vec::new();
+        self.expand(address, &mut Vec::new(), &mut mailboxes)?;
+        Ok(mailboxes)
+    }
+
+    /// Add the mailboxes of an address to `mailboxes`. `path` is the aliases followed to reach this address.
+    fn expand(
+        &self,
+        address: &Address,
+        path: &mut Vec<String>,
+        mailboxes: &mut Vec<Address>,
+    ) -> Result<(), ResolveError> {
+        let key = self.key(address);
+        if path.contains(&key) {
+            return Err(ResolveError::Loop(address.clone()));
+        }
+        if path.len() >= MAX_DEPTH {
+            return Err(ResolveError::TooDeep);
+        }
+
+        let targets = match self.targets(address, &key)? {
+            Some(targets) => targets,
+            None => {
+                if !mailboxes.contains(address) {
+                    mailboxes.push(address.clone());
+                }
+                return Ok(());
+            }
+        };
+        path.push(key);
+        for target in targets {
+            if path.last() == Some(&self.key(&target)) {
+                if !mailboxes.contains(address) {
+                    mailboxes.push(address.clone());
+                }
+            } else {
+                self.expand(&target, path, mailboxes)?;
+            }
+        }
+        path.pop();
+        Ok(())
+    }
+
+    /// The addresses an address is an alias of, or `None` if it is a mailbox.
+    fn targets(&self, address: &Address, key: &str) -> Result<Option<Vec<Address>>, ResolveError> {
+        if let Some(i) = self.index.get(key) {
+            return Ok(Some(self.aliases[*i].1.clone()));
+        }
+        if let (Some(detail), Some(separator)) = (
+            address.detail(&self.local_parts),
+            self.local_parts.separator(),
+        ) {
+            if let Some(i) = self
+                .index
+                .get(&self.key(&address.mailbox(&self.local_parts)))
+            {
+                let targets = self.aliases[*i].1.iter().map(|target| {
+                    match target.detail(&self.local_parts) {
+                        Some(_) => target.clone(),
+                        None => Address {
+                            local_part: format!("{}{}{}", target.local_part, separator, detail),
+                            domain: target.domain.clone(),
+                        },
+                    }
+                });
+                return Ok(Some(targets.collect()));
+            }
+        }
+        for (regex, templates) in &self.patterns {
+            if let Some(captures) = regex.captures(key) {
+                let mut targets = Vec::with_capacity(templates.len());
+                for template in templates {
+                    let mut target = String::new();
+                    captures.expand(template, &mut target);
+                    targets
+                        .push(Address::parse(&target).ok_or(ResolveError::InvalidAddress(target))?);
+                }
+                return Ok(Some(targets));
+            }
+        }
+        Ok(self.catch_alls.get(&address.domain.to_lowercase()).cloned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addresses(addresses: &[&str]) -> Vec<Address> {
+        addresses
+            .iter()
+            .map(|address| Address::parse(address).unwrap())
+            .collect()
+    }
+
+    fn resolve(table: &AliasTable, address: &str) -> Result<Vec<Address>, ResolveError> {
+        table.resolve(&Address::parse(address).unwrap())
+    }
+
+    #[test]
+    fn test_aliases() {
+        let table = AliasTable::parse(
+            "# aliases\n\
+             domain Example.com\n\
+             domain example.org\n\
+             \n\
+             postmaster@example.com: alice@example.com\n\
+             Team@example.com: alice@example.com, bob@example.com, postmaster@example.com\n\
+             bob@example.com: bob@example.com, bob@example.net\n\
+             /^(.+)-owner@example\\.com$/: ${1}-admin@example.com\n\
+             @example.org: carol@example.com\n\
+             loop@example.com: loop2@example.com\n\
+             loop2@example.com: loop@example.com\n\
+             /^bad@/: not an address\n",
+        )
+        .unwrap();
+
+        assert!(table.is_local("example.COM"));
+        assert!(!table.is_local("example.net"));
+        assert_eq!(
+            resolve(&table, "alice@example.com"),
+            Ok(addresses(&["alice@example.com"]))
+        );
+        assert_eq!(
+            resolve(&table, "postmaster@example.com"),
+            Ok(addresses(&["alice@example.com"]))
+        );
+        assert_eq!(
+            resolve(&table, "team@EXAMPLE.com"),
+            Ok(addresses(&[
+                "alice@example.com",
+                "bob@example.com",
+                "bob@example.net"
+            ]))
+        );
+        assert_eq!(
+            resolve(&table, "list-owner@example.com"),
+            Ok(addresses(&["list-admin@example.com"]))
+        );
+        assert_eq!(
+            resolve(&table, "anyone@example.org"),
+            Ok(addresses(&["carol@example.com"]))
+        );
+        assert_eq!(
+            resolve(&table, "loop@example.com"),
+            Err(ResolveError::Loop(
+                addresses(&["loop@example.com"])[0].clone()
+            ))
+        );
+        assert!(matches!(
+            resolve(&table, "bad@example.com"),
+            Err(ResolveError::InvalidAddress(_))
+        ));
+
+        assert_eq!(
+            resolve(&table, "Team+News@example.com"),
+            Ok(addresses(&[
+                "alice+news@example.com",
+                "bob+news@example.com",
+                "bob+news@example.net"
+            ]))
+        );
+
+        let deep: String = (0..30)
+            .map(|i| format!("a{}@example.com: a{}@example.com\n", i, i + 1))
+            .collect();
+        assert_eq!(
+            resolve(&AliasTable::parse(&deep).unwrap(), "a0@example.com"),
+            Err(ResolveError::TooDeep)
+        );
+
+        // the aliases follow the local part rules of the table
+        let table = AliasTable::parse("Alice@example.com: alice.smith@example.com\n").unwrap();
+        assert_eq!(
+            resolve(&table, "alice-x@example.com"),
+            Ok(addresses(&["alice-x@example.com"]))
+        );
+        let table = table.local_parts(
+            LocalPartRules::new()
+                .separators(&['-', '+'])
+                .fold_case(false),
+        );
+        assert_eq!(
+            resolve(&table, "alice@example.com"),
+            Ok(addresses(&["alice@example.com"]))
+        );
+        assert_eq!(
+            resolve(&table, "Alice-x@example.com"),
+            Ok(addresses(&["alice.smith-x@example.com"]))
+        );
+    }
+
+    #[test]
+    fn test_invalid_tables() {
+        assert!(matches!(
+            AliasTable::parse("domain example.com\nalice@example.com\n"),
+            Err(AliasError::Syntax { line: 2, .. })
+        ));
+        assert!(matches!(
+            AliasTable::parse("alice@example.com: not an address"),
+            Err(AliasError::Syntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            AliasTable::parse("alice@example.com:"),
+            Err(AliasError::Syntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            AliasTable::parse("/(/: alice@example.com"),
+            Err(AliasError::Syntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            AliasTable::from_file("/nonexistent/aliases"),
+            Err(AliasError::Io { .. })
+        ));
+    }
+}
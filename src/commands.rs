@@ -6,6 +6,9 @@ use std::borrow::Cow;
 pub enum ServerIdentity<'a> {
     Domain(&'a str),
     Ipv4(&'a str),
+    Ipv6(&'a str),
+    /// A general address literal (RFC 5321 section 4.1.3): a standardized tag and its content.
+    General(&'a str, &'a str),
 }
 
 impl<'a> std::fmt::Display for ServerIdentity<'a> {
@@ -13,6 +16,8 @@ impl<'a> std::fmt::Display for ServerIdentity<'a> {
         match self {
             ServerIdentity::Domain(s) => write!(f, "{}", s),
             ServerIdentity::Ipv4(s) => write!(f, "[{}]", s),
+            ServerIdentity::Ipv6(s) => write!(f, "[IPv6:{}]", s),
+            ServerIdentity::General(tag, content) => write!(f, "[{}:{}]", tag, content),
         }
     }
 }
@@ -32,6 +37,124 @@ impl<'a> std::fmt::Display for LocalPart<'a> {
     }
 }
 
+impl<'a> LocalPart<'a> {
+    /// Build a local part from its unquoted value, which is quoted only if it is not a valid dot-string.
+    pub fn from_unquoted(local_part: &'a str) -> LocalPart<'a> {
+        match parsing::dot_string(local_part) {
+            Ok(("", _)) => LocalPart::DotString(local_part),
+            _ => LocalPart::QuotedString(local_part.to_string()),
+        }
+    }
+
+    /// The unquoted value of the local part.
+    pub fn as_str(&self) -> &str {
+        match self {
+            LocalPart::DotString(s) => s,
+            LocalPart::QuotedString(s) => s,
+        }
+    }
+
+    /// The local part as it would be written in a path, with quotes and backslashes if needed.
+    pub fn to_quoted(&self) -> String {
+        match self {
+            LocalPart::DotString(s) => s.to_string(),
+            LocalPart::QuotedString(s) => {
+                let mut quoted = String::with_capacity(s.len() + 2);
+                quoted.push('"');
+                for character in s.chars() {
+                    if character == '"' || character == '\\' {
+                        quoted.push('\\');
+                    }
+                    quoted.push(character);
+                }
+                quoted.push('"');
+                quoted
+            }
+        }
+    }
+
+    /// Whether the local part can only be written with quotes. `"alice"` is the same local part as `alice`.
+    fn needs_quotes(&self) -> bool {
+        !matches!(parsing::dot_string(self.as_str()), Ok(("", _)))
+    }
+
+    /// The local part in the form used to compare it with others, according to the `rules`.
+    pub fn normalize(&self, rules: &LocalPartRules) -> String {
+        match self.needs_quotes() && !rules.normalize_quoted {
+            false if rules.fold_case => self.as_str().to_lowercase(),
+            _ => self.as_str().to_string(),
+        }
+    }
+
+    /// The normalized user and detail of the local part: `Alice+Lists` is `("alice", Some("lists"))` with the default rules.
+    pub fn split_detail(&self, rules: &LocalPartRules) -> (String, Option<String>) {
+        let local_part = self.normalize(rules);
+        if self.needs_quotes() && !rules.normalize_quoted {
+            return (local_part, None);
+        }
+        match local_part
+            .char_indices()
+            .find(|(_, c)| rules.separators.contains(c))
+        {
+            Some((p, separator)) => (
+                local_part[..p].to_string(),
+                Some(local_part[p + separator.len_utf8()..].to_string()),
+            ),
+            None => (local_part, None),
+        }
+    }
+}
+
+/// How local parts are compared, and split into a user and a detail like `alice+lists` (subaddressing, RFC 5233).
+///
+/// By default, the detail follows a `+`, local parts are compared in lowercase,
+/// and those needing quotes like `"alice smith"` are taken literally.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalPartRules {
+    separators: Vec<char>,
+    fold_case: bool,
+    normalize_quoted: bool,
+}
+
+impl Default for LocalPartRules {
+    fn default() -> LocalPartRules {
+        LocalPartRules {
+            separators: vec!['+'],
+            fold_case: true,
+            normalize_quoted: false,
+        }
+    }
+}
+
+impl LocalPartRules {
+    pub fn new() -> LocalPartRules {
+        LocalPartRules::default()
+    }
+
+    /// The characters separating the user from the detail. None disables subaddressing.
+    pub fn separators(mut self, separators: &[char]) -> Self {
+        self.separators = separators.to_vec();
+        self
+    }
+
+    /// Compare local parts in lowercase, although RFC 5321 lets servers treat them as case-sensitive.
+    pub fn fold_case(mut self, enabled: bool) -> Self {
+        self.fold_case = enabled;
+        self
+    }
+
+    /// Also fold and split the local parts needing quotes.
+    pub fn normalize_quoted(mut self, enabled: bool) -> Self {
+        self.normalize_quoted = enabled;
+        self
+    }
+
+    /// The separator added before a detail.
+    pub(crate) fn separator(&self) -> Option<char> {
+        self.separators.first().copied()
+    }
+}
+
 /*#[derive(Debug, PartialEq)]
 pub enum Cow<'a, str> {
     Atom(&'a str),
@@ -58,7 +181,7 @@ impl<'a> std::fmt::Display for Cow<'a, str> {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Path<'a>(pub Vec<&'a str>, pub (LocalPart<'a>, ServerIdentity<'a>));
-type PARAM<'a> = (&'a str, Option<&'a str>);
+type Param<'a> = (&'a str, Option<&'a str>);
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Recipient<'a> {
@@ -81,8 +204,8 @@ impl<'a> std::fmt::Display for Recipient<'a> {
 pub enum Command<'a> {
     Helo(&'a str),
     Ehlo(ServerIdentity<'a>),
-    From(Option<Path<'a>>, Vec<PARAM<'a>>),
-    To(Recipient<'a>, Vec<PARAM<'a>>),
+    From(Option<Path<'a>>, Vec<Param<'a>>),
+    To(Recipient<'a>, Vec<Param<'a>>),
     Data,
     Reset,
     Verify(Cow<'a, str>),
@@ -91,20 +214,102 @@ pub enum Command<'a> {
     Noop(Option<Cow<'a, str>>),
     Quit,
     StartTLS,
+    /// AUTH (RFC 4954): the SASL mechanism and the optional initial response, still encoded in base64.
+    Auth(&'a str, Option<&'a str>),
+}
+
+impl<'a> std::fmt::Display for Path<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Path(source_route, (local_part, identity)) = self;
+        write!(f, "<")?;
+        if !source_route.is_empty() {
+            let route: Vec<String> = source_route
+                .iter()
+                .map(|domain| format!("@{}", domain))
+                .collect();
+            write!(f, "{}:", route.join(","))?;
+        }
+        write!(f, "{}@{}>", local_part.to_quoted(), identity)
+    }
+}
+
+/// The command as sent by a client, without the CRLF.
+impl<'a> std::fmt::Display for Command<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Helo(domain) => write!(f, "HELO {}", domain),
+            Command::Ehlo(identity) => write!(f, "EHLO {}", identity),
+            Command::From(path, parameters) => {
+                match path {
+                    Some(path) => write!(f, "MAIL FROM:{}", path)?,
+                    None => write!(f, "MAIL FROM:<>")?,
+                }
+                write_parameters(f, parameters)
+            }
+            Command::To(recipient, parameters) => {
+                match recipient {
+                    Recipient::LocalPostmaster => write!(f, "RCPT TO:<postmaster>")?,
+                    Recipient::Postmaster(domain) => write!(f, "RCPT TO:<postmaster@{}>", domain)?,
+                    Recipient::Path(path) => write!(f, "RCPT TO:{}", path)?,
+                }
+                write_parameters(f, parameters)
+            }
+            Command::Data => write!(f, "DATA"),
+            Command::Reset => write!(f, "RSET"),
+            Command::Verify(argument) => write!(f, "VRFY {}", quote_argument(argument)),
+            Command::Expand(argument) => write!(f, "EXPN {}", quote_argument(argument)),
+            Command::Help(Some(argument)) => write!(f, "HELP {}", quote_argument(argument)),
+            Command::Help(None) => write!(f, "HELP"),
+            Command::Noop(Some(argument)) => write!(f, "NOOP {}", quote_argument(argument)),
+            Command::Noop(None) => write!(f, "NOOP"),
+            Command::Quit => write!(f, "QUIT"),
+            Command::StartTLS => write!(f, "STARTTLS"),
+            Command::Auth(mechanism, Some(initial_response)) => {
+                write!(f, "AUTH {} {}", mechanism, initial_response)
+            }
+            Command::Auth(mechanism, None) => write!(f, "AUTH {}", mechanism),
+        }
+    }
 }
 
-impl<'a> ToString for Command<'a> {
-    fn to_string(&self) -> String {
-        todo!()
+fn write_parameters(f: &mut std::fmt::Formatter<'_>, parameters: &[Param<'_>]) -> std::fmt::Result {
+    for parameter in parameters {
+        match parameter {
+            (keyword, Some(value)) => write!(f, " {}={}", keyword, value)?,
+            (keyword, None) => write!(f, " {}", keyword)?,
+        }
+    }
+    Ok(())
+}
+
+/// An argument of VRFY, EXPN, HELP or NOOP, quoted unless it is an atom or a mailbox.
+fn quote_argument(argument: &str) -> String {
+    let unbracketed = argument
+        .strip_prefix('<')
+        .and_then(|a| a.strip_suffix('>'))
+        .unwrap_or(argument);
+    let atom = !argument.is_empty() && argument.chars().all(parsing::is_atext);
+    match atom || parse_mailbox(unbracketed).is_some() {
+        true => argument.to_string(),
+        false => LocalPart::QuotedString(argument.to_string()).to_quoted(),
     }
 }
 
 impl<'a> Command<'a> {
-    pub fn from_str(input: &'a str) -> Result<Command<'a>, parsing::Error> {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(input: &'a str) -> Result<Command<'a>, parsing::Error<'a>> {
         parsing::command(input)
     }
 }
 
+/// Parse a mailbox written like in a path, without the angle brackets: `alice@example.com`.
+pub(crate) fn parse_mailbox(input: &str) -> Option<(LocalPart<'_>, ServerIdentity<'_>)> {
+    match parsing::mailbox(input) {
+        Ok(("", mailbox)) => Some(mailbox),
+        _ => None,
+    }
+}
+
 mod parsing {
     use super::*;
     use nom::bytes::complete::{tag, tag_no_case, take_while, take_while1};
@@ -115,6 +320,7 @@ mod parsing {
         InvalidCommand,
         InvalidDomain,
         InvalidIpv4Address,
+        InvalidIpv6Address,
         ExpectedEndOfInput,
         InvalidIdentity,
         ExpectedCrlf,
@@ -123,7 +329,11 @@ mod parsing {
         Known(&'a str),
     }
 
-    fn is_atext(character: char) -> bool {
+    pub(super) fn is_atext(character: char) -> bool {
+        character.is_ascii() && is_ascii_atext(character)
+    }
+
+    fn is_ascii_atext(character: char) -> bool {
         (character as u8 >= 0x41 && character as u8 <= 0x5A)
             || (character as u8 >= 0x61 && character as u8 <= 0x7A)
             || (character as u8 >= 0x30 && character as u8 <= 0x39)
@@ -149,12 +359,13 @@ mod parsing {
     }
 
     fn is_qtext_smtp(character: char) -> bool {
-        (character as u8 >= 32 && character as u8 <= 33)
-            || (character as u8 >= 35 && character as u8 <= 91)
-            || (character as u8 >= 93 && character as u8 <= 126)
+        character.is_ascii()
+            && ((character as u8 >= 32 && character as u8 <= 33)
+                || (character as u8 >= 35 && character as u8 <= 91)
+                || (character as u8 >= 93 && character as u8 <= 126))
     }
 
-    fn dot_string(input: &str) -> Result<(&str, &str), Error> {
+    pub(super) fn dot_string(input: &str) -> Result<(&str, &str), Error<'_>> {
         let mut idx = 0;
         let mut expects_text = true;
 
@@ -170,13 +381,19 @@ mod parsing {
             } else if expects_text {
                 expects_text = false;
             }
-            idx += 1;
+            idx += character.len_utf8();
+        }
+
+        if expects_text {
+            return Err(Error::Known(
+                "A dot-string cannot be empty or end with a dot.",
+            ));
         }
 
         Ok((&input[idx..], &input[..idx]))
     }
 
-    fn quoted_string(mut input: &str) -> Result<(&str, String), Error> {
+    fn quoted_string(mut input: &str) -> Result<(&str, String), Error<'_>> {
         input = tag::<_, _, ()>("\"")(input)
             .map_err(|_| {
                 Error::Known("Expected double quote at the beginning of a quoted string.")
@@ -216,7 +433,7 @@ mod parsing {
         ))
     }
 
-    fn local_part(input: &str) -> Result<(&str, LocalPart), Error> {
+    fn local_part(input: &str) -> Result<(&str, LocalPart<'_>), Error<'_>> {
         if let Ok((i, s)) = dot_string(input) {
             Ok((i, LocalPart::DotString(s)))
         } else if let Ok((i, s)) = quoted_string(input) {
@@ -228,7 +445,9 @@ mod parsing {
         }
     }
 
-    fn mailbox(input: &str) -> Result<(&str, (LocalPart, ServerIdentity)), Error> {
+    pub(super) fn mailbox(
+        input: &str,
+    ) -> Result<(&str, (LocalPart<'_>, ServerIdentity<'_>)), Error<'_>> {
         let (mut input, local_part) = local_part(input)?;
         input = tag::<_, _, ()>("@")(input)
             .map_err(|_| Error::Known("Expecting a '@' in an email address."))?
@@ -237,7 +456,7 @@ mod parsing {
         Ok((input, (local_part, identity)))
     }
 
-    fn domain(input: &str) -> Result<(&str, &str), Error> {
+    fn domain(input: &str) -> Result<(&str, &str), Error<'_>> {
         let point_allowed = Cell::new(false);
         let hyphen_allowed = Cell::new(false);
         let end_allowed = Cell::new(false);
@@ -267,7 +486,7 @@ mod parsing {
         Ok(r)
     }
 
-    fn ipv4_address(input: &str) -> Result<(&str, &str), Error> {
+    fn ipv4_address(input: &str) -> Result<(&str, &str), Error<'_>> {
         let (input, _useless) = tag::<_, _, ()>("[")(input).map_err(|_| Error::Unknown)?;
 
         let digit_idx = Cell::new(0);
@@ -297,11 +516,11 @@ mod parsing {
                             '5' => allow_high_third_digit.set(false),
                             _ => (),
                         },
-                        2 if !allow_high_third_digit.get() => {
-                            if c == '6' || c == '7' || c == '8' || c == '9' {
-                                error.set(true);
-                                return false;
-                            }
+                        2 if !allow_high_third_digit.get()
+                            && (c == '6' || c == '7' || c == '8' || c == '9') =>
+                        {
+                            error.set(true);
+                            return false;
                         }
                         _ => (),
                     };
@@ -339,9 +558,56 @@ mod parsing {
         Ok((input, addr))
     }
 
-    fn identity(input: &str) -> Result<(&str, ServerIdentity), Error> {
+    fn ipv6_address(input: &str) -> Result<(&str, &str), Error<'_>> {
+        let (input, _useless) =
+            tag_no_case::<_, _, ()>("[IPv6:")(input).map_err(|_| Error::Unknown)?;
+        let (input, addr) =
+            take_while1::<_, _, ()>(|c: char| c.is_ascii_hexdigit() || c == ':' || c == '.')(input)
+                .map_err(|_| Error::InvalidIpv6Address)?;
+        if addr.parse::<std::net::Ipv6Addr>().is_err() {
+            return Err(Error::InvalidIpv6Address);
+        }
+        let (input, _useless) =
+            tag::<_, _, ()>("]")(input).map_err(|_| Error::InvalidIpv6Address)?;
+
+        Ok((input, addr))
+    }
+
+    fn general_address_literal(input: &str) -> Result<(&str, (&str, &str)), Error<'_>> {
+        let (input, _useless) = tag::<_, _, ()>("[")(input).map_err(|_| Error::Unknown)?;
+        let (input, standardized_tag) =
+            take_while1::<_, _, ()>(|c: char| c.is_ascii_alphanumeric() || c == '-')(input)
+                .map_err(|_| {
+                    Error::Known("Expected a standardized tag in a general address literal.")
+                })?;
+        if standardized_tag.starts_with('-') || standardized_tag.ends_with('-') {
+            return Err(Error::Known(
+                "A standardized tag cannot start or end with a '-'.",
+            ));
+        }
+        if standardized_tag.eq_ignore_ascii_case("IPv6") {
+            return Err(Error::InvalidIpv6Address);
+        }
+        let (input, _useless) = tag::<_, _, ()>(":")(input).map_err(|_| {
+            Error::Known("Expected ':' after the standardized tag of a general address literal.")
+        })?;
+        let (input, content) = take_while1::<_, _, ()>(|c: char| {
+            (c as u8 >= 33 && c as u8 <= 90) || (c as u8 >= 94 && c as u8 <= 126)
+        })(input)
+        .map_err(|_| Error::Known("Empty general address literal."))?;
+        let (input, _useless) = tag::<_, _, ()>("]")(input)
+            .map_err(|_| Error::Known("Expected ']' at the end of a general address literal."))?;
+
+        Ok((input, (standardized_tag, content)))
+    }
+
+    fn identity(input: &str) -> Result<(&str, ServerIdentity<'_>), Error<'_>> {
         if let Ok((input, addr)) = ipv4_address(input) {
             Ok((input, ServerIdentity::Ipv4(addr)))
+        } else if let Ok((input, addr)) = ipv6_address(input) {
+            Ok((input, ServerIdentity::Ipv6(addr)))
+        } else if let Ok((input, (standardized_tag, content))) = general_address_literal(input) {
+            Ok((input, ServerIdentity::General(standardized_tag, content)))
         } else if let Ok((input, domain)) = domain(input) {
             Ok((input, ServerIdentity::Domain(domain)))
         } else {
@@ -349,7 +615,7 @@ mod parsing {
         }
     }
 
-    fn reverse_path(input: &str) -> Result<(&str, Option<Path>), Error> {
+    fn reverse_path(input: &str) -> Result<(&str, Option<Path<'_>>), Error<'_>> {
         if let Ok((i, _p)) = tag::<_, _, ()>("<>")(input) {
             return Ok((i, None));
         }
@@ -358,7 +624,7 @@ mod parsing {
         Ok((input, Some(path)))
     }
 
-    fn source_route(mut input: &str) -> Result<(&str, Vec<&str>), Error> {
+    fn source_route(mut input: &str) -> Result<(&str, Vec<&str>), Error<'_>> {
         input = tag::<_, _, ()>("@")(input)
             .map_err(|_| Error::Known("Expected '@' at the beginning of a source route."))?
             .0;
@@ -389,7 +655,7 @@ mod parsing {
         Ok((input, domains))
     }
 
-    fn path(input: &str) -> Result<(&str, Path), Error> {
+    fn path(input: &str) -> Result<(&str, Path<'_>), Error<'_>> {
         let (mut input, _begin) = tag::<_, _, ()>("<")(input)
             .map_err(|_| Error::Known("Expected '<' at the beginning of a path."))?;
         let source_route = match source_route(input) {
@@ -406,7 +672,7 @@ mod parsing {
         Ok((input, Path(source_route, mailbox)))
     }
 
-    fn parameters(input: &str) -> Result<(&str, Vec<PARAM>), Error> {
+    fn parameters(input: &str) -> Result<(&str, Vec<Param<'_>>), Error<'_>> {
         let mut parameters = Vec::new();
         let (mut input, first_param) = esmtp_param(input)?;
         parameters.push(first_param);
@@ -430,7 +696,7 @@ mod parsing {
         Ok((input, parameters))
     }
 
-    fn esmtp_param(input: &str) -> Result<(&str, PARAM), Error> {
+    fn esmtp_param(input: &str) -> Result<(&str, Param<'_>), Error<'_>> {
         let (mut input, keyword) = esmtp_keyword(input)?;
         match tag::<_, _, ()>("=")(input) {
             Ok((i, _)) => input = i,
@@ -440,7 +706,7 @@ mod parsing {
         Ok((input, (keyword, Some(value))))
     }
 
-    fn esmtp_keyword(input: &str) -> Result<(&str, &str), Error> {
+    fn esmtp_keyword(input: &str) -> Result<(&str, &str), Error<'_>> {
         let (input, keyword) = take_while1::<_, _, ()>(|character: char| {
             (character as u8 >= 0x41 && character as u8 <= 0x5A)
                 || (character as u8 >= 0x61 && character as u8 <= 0x7A)
@@ -456,14 +722,14 @@ mod parsing {
         Ok((input, keyword))
     }
 
-    fn esmtp_value(input: &str) -> Result<(&str, &str), Error> {
-        Ok(take_while1::<_, _, ()>(|character: char| {
+    fn esmtp_value(input: &str) -> Result<(&str, &str), Error<'_>> {
+        take_while1::<_, _, ()>(|character: char| {
             character as u8 >= 33 && character as u8 <= 128 && character as u8 != 61
         })(input)
-        .map_err(|_| Error::Known("Empty esmtp_value"))?)
+        .map_err(|_| Error::Known("Empty esmtp_value"))
     }
 
-    fn string(input: &str) -> Result<(&str, Cow<str>), Error> {
+    fn string(input: &str) -> Result<(&str, Cow<'_, str>), Error<'_>> {
         if let Ok((input, s)) = take_while1::<_, _, ()>(is_atext)(input) {
             return Ok((input, Cow::Borrowed(s)));
         }
@@ -475,7 +741,21 @@ mod parsing {
         Err(Error::Known("Expected a string."))
     }
 
-    fn recipient(input: &str) -> Result<(&str, Recipient), Error> {
+    /// The argument of VRFY and EXPN: a string, or a mailbox with or without angle brackets
+    /// as many clients send (RFC 5321 section 3.5.3).
+    fn string_or_mailbox(input: &str) -> Result<(&str, Cow<'_, str>), Error<'_>> {
+        let (argument, rest) = input.split_at(input.find("\r\n").unwrap_or(input.len()));
+        let unbracketed = argument
+            .strip_prefix('<')
+            .and_then(|a| a.strip_suffix('>'))
+            .unwrap_or(argument);
+        if let Ok(("", _)) = mailbox(unbracketed) {
+            return Ok((rest, Cow::Borrowed(argument)));
+        }
+        string(input)
+    }
+
+    fn recipient(input: &str) -> Result<(&str, Recipient<'_>), Error<'_>> {
         if let Ok((input, _)) = tag_no_case::<_, _, ()>("<postmaster@")(input) {
             if let Ok((input, domain)) = domain(input) {
                 if let Ok((input, _)) = tag::<_, _, ()>(">")(input) {
@@ -497,7 +777,7 @@ mod parsing {
 
     // commands
 
-    fn helo(input: &str) -> Result<Command, Error> {
+    fn helo(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _command_name) =
             tag_no_case::<_, _, ()>("HELO ")(input).map_err(|_| Error::CommandName)?;
         let (input, domain) = domain(input)?;
@@ -508,7 +788,7 @@ mod parsing {
         Ok(Command::Helo(domain))
     }
 
-    fn ehlo(input: &str) -> Result<Command, Error> {
+    fn ehlo(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _command_name) =
             tag_no_case::<_, _, ()>("EHLO ")(input).map_err(|_| Error::CommandName)?;
         let (input, identity) = identity(input)?;
@@ -519,7 +799,7 @@ mod parsing {
         Ok(Command::Ehlo(identity))
     }
 
-    fn to(input: &str) -> Result<Command, Error> {
+    fn to(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _command_name) =
             tag_no_case::<_, _, ()>("RCPT TO:")(input).map_err(|_| Error::CommandName)?;
         let (mut input, recipient) = recipient(input)?;
@@ -540,7 +820,7 @@ mod parsing {
         Ok(Command::To(recipient, mail_parameters))
     }
 
-    fn from(input: &str) -> Result<Command, Error> {
+    fn from(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _command_name) =
             tag_no_case::<_, _, ()>("MAIL FROM:")(input).map_err(|_| Error::CommandName)?;
         let (mut input, path) = reverse_path(input)?;
@@ -561,7 +841,7 @@ mod parsing {
         Ok(Command::From(path, mail_parameters))
     }
 
-    fn data(input: &str) -> Result<Command, Error> {
+    fn data(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _) =
             tag_no_case::<_, _, ()>("DATA\r\n")(input).map_err(|_| Error::CommandName)?;
         if !input.is_empty() {
@@ -571,7 +851,7 @@ mod parsing {
         Ok(Command::Data)
     }
 
-    fn start_tls(input: &str) -> Result<Command, Error> {
+    fn start_tls(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _) =
             tag_no_case::<_, _, ()>("STARTTLS\r\n")(input).map_err(|_| Error::CommandName)?;
         if !input.is_empty() {
@@ -581,7 +861,36 @@ mod parsing {
         Ok(Command::StartTLS)
     }
 
-    fn quit(input: &str) -> Result<Command, Error> {
+    fn auth(input: &str) -> Result<Command<'_>, Error<'_>> {
+        let (input, _) = tag_no_case::<_, _, ()>("AUTH ")(input).map_err(|_| Error::CommandName)?;
+        let (mut input, mechanism) =
+            take_while1::<_, _, ()>(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_')(
+                input,
+            )
+            .map_err(|_| Error::Known("Expected a SASL mechanism"))?;
+        if mechanism.len() > 20 {
+            return Err(Error::Known("SASL mechanism too long"));
+        }
+
+        let initial_response = if let Ok((i, _)) = tag::<_, _, ()>(" ")(input) {
+            let (i, initial_response) = take_while1::<_, _, ()>(|c: char| {
+                c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '='
+            })(i)
+            .map_err(|_| Error::Known("Expected a base64 initial response"))?;
+            input = i;
+            Some(initial_response)
+        } else {
+            None
+        };
+
+        let (input, _end) = tag::<_, _, ()>("\r\n")(input).map_err(|_| Error::ExpectedCrlf)?;
+        if !input.is_empty() {
+            return Err(Error::ExpectedEndOfInput);
+        }
+        Ok(Command::Auth(mechanism, initial_response))
+    }
+
+    fn quit(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _) =
             tag_no_case::<_, _, ()>("QUIT\r\n")(input).map_err(|_| Error::CommandName)?;
         if !input.is_empty() {
@@ -591,7 +900,7 @@ mod parsing {
         Ok(Command::Quit)
     }
 
-    fn reset(input: &str) -> Result<Command, Error> {
+    fn reset(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _) =
             tag_no_case::<_, _, ()>("RSET\r\n")(input).map_err(|_| Error::CommandName)?;
         if !input.is_empty() {
@@ -601,9 +910,9 @@ mod parsing {
         Ok(Command::Reset)
     }
 
-    fn verify(input: &str) -> Result<Command, Error> {
+    fn verify(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _) = tag_no_case::<_, _, ()>("VRFY ")(input).map_err(|_| Error::CommandName)?;
-        let (input, string) = string(input)?;
+        let (input, string) = string_or_mailbox(input)?;
         let (input, _end) = tag::<_, _, ()>("\r\n")(input).map_err(|_| Error::ExpectedCrlf)?;
         if !input.is_empty() {
             return Err(Error::ExpectedEndOfInput);
@@ -611,9 +920,9 @@ mod parsing {
         Ok(Command::Verify(string))
     }
 
-    fn expand(input: &str) -> Result<Command, Error> {
+    fn expand(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (input, _) = tag_no_case::<_, _, ()>("EXPN ")(input).map_err(|_| Error::CommandName)?;
-        let (input, mailing_list) = string(input)?;
+        let (input, mailing_list) = string_or_mailbox(input)?;
         let (input, _end) = tag::<_, _, ()>("\r\n")(input).map_err(|_| Error::ExpectedCrlf)?;
         if !input.is_empty() {
             return Err(Error::ExpectedEndOfInput);
@@ -621,7 +930,7 @@ mod parsing {
         Ok(Command::Expand(mailing_list))
     }
 
-    fn help(input: &str) -> Result<Command, Error> {
+    fn help(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (mut input, _) =
             tag_no_case::<_, _, ()>("HELP")(input).map_err(|_| Error::CommandName)?;
         let command = if let Ok((i, _)) = tag::<_, _, ()>(" ")(input) {
@@ -639,7 +948,7 @@ mod parsing {
         Ok(Command::Help(command))
     }
 
-    fn noop(input: &str) -> Result<Command, Error> {
+    fn noop(input: &str) -> Result<Command<'_>, Error<'_>> {
         let (mut input, _) =
             tag_no_case::<_, _, ()>("NOOP")(input).map_err(|_| Error::CommandName)?;
         let parameter = if let Ok((i, _)) = tag::<_, _, ()>(" ")(input) {
@@ -657,7 +966,7 @@ mod parsing {
         Ok(Command::Noop(parameter))
     }
 
-    pub fn command(input: &str) -> Result<Command, Error> {
+    pub fn command(input: &str) -> Result<Command<'_>, Error<'_>> {
         if let Ok(command) = ehlo(input) {
             Ok(command)
         } else if let Ok(command) = start_tls(input) {
@@ -682,6 +991,8 @@ mod parsing {
             Ok(command)
         } else if let Ok(command) = help(input) {
             Ok(command)
+        } else if let Ok(command) = auth(input) {
+            Ok(command)
         } else {
             Err(Error::Known("No command matching"))
         }
@@ -714,6 +1025,32 @@ mod parsing {
             // todo add more cases
         }
 
+        #[test]
+        fn test_display() {
+            for line in [
+                "HELO example.com",
+                "EHLO [IPv6:2001:db8::1]",
+                "MAIL FROM:<>",
+                "MAIL FROM:<\"alice smith\"@example.com> SIZE=1000 BODY=8BITMIME",
+                "RCPT TO:<@relay.example.net,@mx.example.net:bob@[192.0.2.1]>",
+                "RCPT TO:<postmaster> NOTIFY=NEVER",
+                "RCPT TO:<postmaster@example.com>",
+                "DATA",
+                "RSET",
+                "VRFY alice",
+                "VRFY <alice@example.com>",
+                "EXPN \"staff list\"",
+                "HELP",
+                "NOOP ping",
+                "QUIT",
+                "STARTTLS",
+                "AUTH EXTERNAL =",
+            ] {
+                let input = format!("{}\r\n", line);
+                assert_eq!(command(&input).unwrap().to_string(), line);
+            }
+        }
+
         #[test]
         fn test_data_reset_quit_starttls() {
             assert_eq!(data("DATA\r\n").unwrap(), Command::Data);
@@ -723,6 +1060,29 @@ mod parsing {
             assert!(data("DATA\r\n email data").is_err());
         }
 
+        #[test]
+        fn test_auth() {
+            assert_eq!(
+                auth("AUTH EXTERNAL\r\n").unwrap(),
+                Command::Auth("EXTERNAL", None)
+            );
+            assert_eq!(
+                auth("auth external =\r\n").unwrap(),
+                Command::Auth("external", Some("="))
+            );
+            assert_eq!(
+                auth("AUTH EXTERNAL cmVsYXk=\r\n").unwrap(),
+                Command::Auth("EXTERNAL", Some("cmVsYXk="))
+            );
+            assert_eq!(
+                command("AUTH PLAIN\r\n").unwrap(),
+                Command::Auth("PLAIN", None)
+            );
+            assert!(auth("AUTH\r\n").is_err());
+            assert!(auth("AUTH EXTERNAL not base64!\r\n").is_err());
+            assert!(auth("AUTH ABCDEFGHIJKLMNOPQRSTUVWXYZ\r\n").is_err());
+        }
+
         #[test]
         fn test_help_and_noop() {
             assert_eq!(help("HELP\r\n").unwrap(), Command::Help(None));
@@ -746,6 +1106,48 @@ mod parsing {
             );
         }
 
+        #[test]
+        fn test_local_part_rules() {
+            let rules = LocalPartRules::default();
+            let split = |local_part: &str, rules: &LocalPartRules| {
+                LocalPart::from_unquoted(local_part).split_detail(rules)
+            };
+            assert_eq!(
+                split("Alice+News", &rules),
+                ("alice".to_string(), Some("news".to_string()))
+            );
+            assert_eq!(split("alice", &rules), ("alice".to_string(), None));
+            assert_eq!(
+                split("alice+", &rules),
+                ("alice".to_string(), Some(String::new()))
+            );
+            assert_eq!(
+                split("Alice Smith+News", &rules),
+                ("Alice Smith+News".to_string(), None)
+            );
+            assert_eq!(
+                LocalPart::QuotedString("Alice+x".to_string()).normalize(&rules),
+                "alice+x"
+            );
+
+            let rules = LocalPartRules::new()
+                .separators(&['-', '+'])
+                .fold_case(false)
+                .normalize_quoted(true);
+            assert_eq!(
+                split("Alice-News+x", &rules),
+                ("Alice".to_string(), Some("News+x".to_string()))
+            );
+            assert_eq!(
+                split("Alice Smith+News", &rules),
+                ("Alice Smith".to_string(), Some("News".to_string()))
+            );
+            assert_eq!(
+                split("alice+news", &LocalPartRules::new().separators(&[])),
+                ("alice+news".to_string(), None)
+            );
+        }
+
         #[test]
         fn test_verify_and_expand() {
             assert_eq!(
@@ -757,6 +1159,11 @@ mod parsing {
                 Command::Verify(Cow::Owned("mubelotix@gmail.com".to_string()))
             );
             assert!(verify("VRFY \"mubelotix\\@gmail\\.com\r\n").is_err());
+            assert_eq!(
+                verify("VRFY <mubelotix@gmail.com>\r\n").unwrap(),
+                Command::Verify(Cow::Borrowed("<mubelotix@gmail.com>"))
+            );
+            assert!(verify("VRFY mubelotix@\r\n").is_err());
 
             assert_eq!(
                 expand("EXPN rustaceans\r\n").unwrap(),
@@ -767,6 +1174,10 @@ mod parsing {
                 Command::Expand(Cow::Owned("Rust lovers".to_string()))
             );
             assert!(expand("EXPN \"unterminated name\r\n").is_err());
+            assert_eq!(
+                expand("EXPN rustaceans@example.com\r\n").unwrap(),
+                Command::Expand(Cow::Borrowed("rustaceans@example.com"))
+            );
         }
 
         #[test]
@@ -900,7 +1311,10 @@ mod parsing {
                 ehlo("EHLO [192.168.1.1]\r\n").unwrap(),
                 Command::Ehlo(ServerIdentity::Ipv4("192.168.1.1"))
             );
-            // todo ipv6
+            assert_eq!(
+                ehlo("EHLO [IPv6:2001:db8::1]\r\n").unwrap(),
+                Command::Ehlo(ServerIdentity::Ipv6("2001:db8::1"))
+            );
             assert!(ehlo("EHLO google.com\r\n invalid ").is_err());
         }
 
@@ -954,6 +1368,20 @@ mod parsing {
                     ServerIdentity::Ipv4("192.168.1.1")
                 )
             );
+            assert_eq!(
+                mailbox("john.snow@[IPv6:::1]").unwrap().1,
+                (
+                    LocalPart::DotString("john.snow"),
+                    ServerIdentity::Ipv6("::1")
+                )
+            );
+            assert_eq!(
+                mailbox("john.snow@[x-tag:some-content]").unwrap().1,
+                (
+                    LocalPart::DotString("john.snow"),
+                    ServerIdentity::General("x-tag", "some-content")
+                )
+            );
             assert_eq!(
                 mailbox("\"John\\ Snow\"@gmail.com").unwrap().1,
                 (
@@ -963,6 +1391,30 @@ mod parsing {
             );
         }
 
+        #[test]
+        fn test_local_part_quoting() {
+            assert_eq!(
+                LocalPart::from_unquoted("john.snow"),
+                LocalPart::DotString("john.snow")
+            );
+            assert_eq!(
+                LocalPart::from_unquoted("John Snow"),
+                LocalPart::QuotedString("John Snow".to_string())
+            );
+            assert_eq!(
+                LocalPart::from_unquoted("john..snow"),
+                LocalPart::QuotedString("john..snow".to_string())
+            );
+            assert_eq!(
+                LocalPart::from_unquoted("john.snow").to_quoted(),
+                "john.snow"
+            );
+            assert_eq!(
+                LocalPart::from_unquoted(r#"say "hi""#).to_quoted(),
+                r#""say \"hi\"""#
+            );
+        }
+
         #[test]
         fn test_source_route() {
             assert_eq!(
@@ -988,6 +1440,9 @@ mod parsing {
             );
             assert_eq!(dot_string("john.snow@example.com").unwrap().1, "john.snow");
             assert!(dot_string("john..snow@example.com").is_err());
+            assert!(dot_string("john.@example.com").is_err());
+            assert!(dot_string("Łukasz@example.com").is_err());
+            assert_eq!(dot_string("john.snowŁ").unwrap(), ("Ł", "john.snow"));
 
             assert_eq!(quoted_string(r#""John\ Snow""#).unwrap().1, "John Snow");
             assert_eq!(
@@ -997,10 +1452,7 @@ mod parsing {
                 "This, is a (valid) email address."
             );
 
-            assert_eq!(
-                string("mubelotix").unwrap().1,
-                Cow::Borrowed("mubelotix")
-            );
+            assert_eq!(string("mubelotix").unwrap().1, Cow::Borrowed("mubelotix"));
             assert_eq!(
                 string(r#""John\ Snow""#).unwrap().1,
                 Cow::Owned::<str>("John Snow".to_string())
@@ -1033,5 +1485,37 @@ mod parsing {
             assert!(ipv4_address("[192.268.1.1]").is_err());
             assert!(ipv4_address("[192.258.1.1]").is_err());
         }
+
+        #[test]
+        fn test_ipv6_address() {
+            assert_eq!(ipv6_address("[IPv6:2001:db8::1]").unwrap().1, "2001:db8::1");
+            assert_eq!(ipv6_address("[ipv6:::1]").unwrap().1, "::1");
+            assert_eq!(
+                ipv6_address("[IPv6:2001:db8:0:0:0:0:2:1]").unwrap().1,
+                "2001:db8:0:0:0:0:2:1"
+            );
+            assert_eq!(
+                ipv6_address("[IPv6:::ffff:192.168.1.1]").unwrap().1,
+                "::ffff:192.168.1.1"
+            );
+            assert!(ipv6_address("[2001:db8::1]").is_err());
+            assert!(ipv6_address("[IPv6:2001:db8:::1]").is_err());
+            assert!(ipv6_address("[IPv6:2001:db8::g]").is_err());
+            assert!(ipv6_address("[IPv6:1:2:3:4:5:6:7:8:9]").is_err());
+            assert!(ipv6_address("[IPv6:2001:db8::1").is_err());
+        }
+
+        #[test]
+        fn test_general_address_literal() {
+            assert_eq!(
+                general_address_literal("[x-tag:content]").unwrap().1,
+                ("x-tag", "content")
+            );
+            assert!(general_address_literal("[IPv6:2001:db8::1]").is_err());
+            assert!(general_address_literal("[-tag:content]").is_err());
+            assert!(general_address_literal("[tag:]").is_err());
+            assert!(general_address_literal("[tag:con[tent]").is_err());
+            assert!(identity("[IPv6:2001:db8:::1]").is_err());
+        }
     }
 }
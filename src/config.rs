@@ -1,11 +1,28 @@
-use tokio_native_tls::TlsAcceptor;
+use crate::aliases::AliasTable;
+use crate::commands::LocalPartRules;
+use crate::dns::{Blocklists, Resolver, SystemResolver};
+use crate::listener::{Listener, TlsMode};
+use crate::policy::{AccessList, RelayPolicy};
+use crate::tls::CertificateHandle;
+use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub(crate) struct Config {
     pub(crate) domain: String,
     pub(crate) server_agent: String,
-    pub(crate) tls_acceptor: Option<TlsAcceptor>,
+    pub(crate) listener: String,
+    pub(crate) certificate: Option<CertificateHandle>,
+    pub(crate) sni_domain: bool,
     pub(crate) tls_required: bool,
+    pub(crate) implicit_tls: bool,
+    pub(crate) auth_required: bool,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) return_path: bool,
+    pub(crate) resolver: Arc<dyn Resolver>,
+    pub(crate) aliases: Option<Arc<AliasTable>>,
+    pub(crate) relay_policy: Option<Arc<RelayPolicy>>,
+    pub(crate) access_list: Option<AccessList>,
+    pub(crate) blocklists: Option<Arc<Blocklists>>,
 }
 
 impl Config {
@@ -13,8 +30,58 @@ impl Config {
         Config {
             domain,
             server_agent: String::from("Rust SMTP server"),
-            tls_acceptor: None,
+            listener: String::from("default"),
+            certificate: None,
+            sni_domain: false,
             tls_required: false,
+            implicit_tls: false,
+            auth_required: false,
+            max_message_size: None,
+            return_path: false,
+            resolver: Arc::new(SystemResolver::new()),
+            aliases: None,
+            relay_policy: None,
+            access_list: None,
+            blocklists: None,
         }
     }
+
+    /// The configuration of the server, restricted by the profile of a listener.
+    pub fn for_listener(&self, listener: &Listener) -> Config {
+        let mut config = self.clone();
+        config.listener = listener.name.clone();
+        match listener.tls_mode {
+            TlsMode::Disabled => config.certificate = None,
+            TlsMode::StartTls => (),
+            TlsMode::StartTlsRequired => config.tls_required = true,
+            TlsMode::Implicit => {
+                config.tls_required = true;
+                config.implicit_tls = true;
+            }
+        }
+        config.auth_required = listener.auth_required;
+        config.max_message_size = listener.max_message_size;
+        config
+    }
+
+    /// Whether a domain is one of the domains the server receives mail for.
+    pub fn is_local(&self, domain: &str) -> bool {
+        domain.eq_ignore_ascii_case(&self.domain)
+            || self
+                .relay_policy
+                .as_ref()
+                .map_or(false, |policy| policy.is_local(domain))
+            || self
+                .aliases
+                .as_ref()
+                .map_or(false, |aliases| aliases.is_local(domain))
+    }
+
+    /// The rules comparing the local parts of the recipients: those of the alias table, or the default ones.
+    pub fn local_parts(&self) -> LocalPartRules {
+        self.aliases
+            .as_ref()
+            .map(|aliases| aliases.rules().clone())
+            .unwrap_or_default()
+    }
 }
@@ -0,0 +1,33 @@
+mod maildir;
+#[cfg(unix)]
+mod mbox;
+mod pipe;
+
+pub use maildir::Maildir;
+#[cfg(unix)]
+pub use mbox::Mbox;
+pub use pipe::Pipe;
+
+/// Whether a part of a local part can be a file name, without leaving the directory of the mailboxes.
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(&['/', '\\', '\0'][..])
+}
+
+/// Whether an error means the disk or the quota of the user is full, reported to the client as temporary.
+fn is_storage_full(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    return matches!(e.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EDQUOT));
+    // ERROR_HANDLE_DISK_FULL and ERROR_DISK_FULL
+    #[cfg(windows)]
+    return matches!(e.raw_os_error(), Some(39) | Some(112));
+    #[cfg(not(any(unix, windows)))]
+    false
+}
+
+/// The error refusing a message that could not be stored.
+fn storage_error(e: std::io::Error) -> crate::SessionError {
+    match is_storage_full(&e) {
+        true => crate::SessionError::InsufficientStorage,
+        false => crate::SessionError::Handler(format!("cannot store the message: {}", e)),
+    }
+}
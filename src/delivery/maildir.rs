@@ -0,0 +1,328 @@
+use super::{is_file_name, storage_error};
+use crate::{
+    commands::{LocalPart, LocalPartRules},
+    error::SessionError,
+    events::EventHandler,
+    message::MessageReader,
+    session::{Address, Session},
+};
+use async_trait::async_trait;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+/// An [EventHandler] delivering a copy of each message to the Maildir of each recipient.
+///
+/// The Maildir of `alice@example.com` is `root/alice`, and `alice+lists@example.com` is delivered
+/// to its Maildir++ folder `root/alice/.lists`. Local parts are compared in lowercase, unless other
+/// [rules](Maildir::local_parts) are set.
+/// Maildirs are created when they receive their first message.
+///
+/// ```no_run
+/// # use smtp_server::{delivery::Maildir, SmtpServer};
+/// let mut server = SmtpServer::new(Maildir::new("/var/mail", "mx.example.com"), "example.com");
+/// ```
+pub struct Maildir {
+    root: PathBuf,
+    hostname: String,
+    counter: AtomicU64,
+    local_parts: LocalPartRules,
+}
+
+impl Maildir {
+    /// Deliver to the Maildirs in `root`. The `hostname` is a part of the name of the files.
+    pub fn new(root: impl Into<PathBuf>, hostname: &str) -> Maildir {
+        Maildir {
+            root: root.into(),
+            // these characters cannot appear in a file name (Maildir specification)
+            hostname: hostname.replace('/', "\\057").replace(':', "\\072"),
+            counter: AtomicU64::new(0),
+            local_parts: LocalPartRules::default(),
+        }
+    }
+
+    /// Map the local parts to Maildirs and folders with these rules.
+    pub fn local_parts(mut self, rules: LocalPartRules) -> Self {
+        self.local_parts = rules;
+        self
+    }
+
+    /// The Maildir of a recipient, or `None` if its local part cannot be used as a directory name.
+    pub fn mailbox(&self, recipient: &Address) -> Option<PathBuf> {
+        let (user, detail) =
+            LocalPart::from_unquoted(&recipient.local_part).split_detail(&self.local_parts);
+        if !is_file_name(&user) {
+            return None;
+        }
+
+        let mut mailbox = self.root.join(user);
+        match detail {
+            Some(detail) if detail.is_empty() => (),
+            Some(detail) if is_file_name(&detail) => mailbox.push(format!(".{}", detail)),
+            Some(_) => return None,
+            None => (),
+        }
+        Some(mailbox)
+    }
+
+    /// A file name no other delivery uses, in the format of the Maildir specification.
+    fn unique_name(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!(
+            "{}.M{}P{}Q{}.{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            std::process::id(),
+            self.counter.fetch_add(1, Ordering::Relaxed),
+            self.hostname
+        )
+    }
+
+    /// Write the message in the `tmp` directory of each mailbox, and add the files to `files` as they are created.
+    async fn store(
+        &self,
+        mailboxes: &[PathBuf],
+        name: &str,
+        message: &mut MessageReader,
+        files: &mut Vec<PathBuf>,
+    ) -> std::io::Result<()> {
+        let (first, others) = match mailboxes.split_first() {
+            Some(mailboxes) => mailboxes,
+            None => return Ok(()),
+        };
+
+        create_maildir(first, &self.root).await?;
+        let path = first.join("tmp").join(name);
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        files.push(path.clone());
+        tokio::io::copy(message, &mut file).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+
+        for mailbox in others {
+            create_maildir(mailbox, &self.root).await?;
+            let copy = mailbox.join("tmp").join(name);
+            files.push(copy.clone());
+            // copies of a message on the same file system share their content
+            if tokio::fs::hard_link(&path, &copy).await.is_err() {
+                tokio::fs::copy(&path, &copy).await?;
+                tokio::fs::File::open(&copy).await?.sync_all().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventHandler for Maildir {
+    async fn on_message_stream(
+        &self,
+        session: &Session,
+        message: &mut MessageReader,
+    ) -> Result<(), SessionError> {
+        let recipients = session
+            .envelope
+            .as_ref()
+            .map(|e| e.recipients.as_slice())
+            .unwrap_or_default();
+        let mut mailboxes = Vec::new();
+        for recipient in recipients {
+            match self.mailbox(recipient) {
+                Some(mailbox) if mailboxes.contains(&mailbox) => (),
+                Some(mailbox) => mailboxes.push(mailbox),
+                None => {
+                    return Err(SessionError::Handler(format!(
+                        "no mailbox for {}",
+                        recipient
+                    )))
+                }
+            }
+        }
+
+        // the message appears in no mailbox until it is completely written in all of them,
+        // and is removed from all of them if it cannot be moved to one
+        let name = self.unique_name();
+        let mut files = Vec::new();
+        let result = match self.store(&mailboxes, &name, message, &mut files).await {
+            Ok(()) => rename_all(&files, &name).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            for file in files {
+                let _ = tokio::fs::remove_file(file).await;
+            }
+            warn!("Delivery of {} to a Maildir failed: {}", name, e);
+            return Err(storage_error(e));
+        }
+        Ok(())
+    }
+}
+
+/// Move the files from the `tmp` directory of their Maildir to the `new` one, and sync the `new` directories
+/// so that the moves survive a crash. On error, the files already moved are removed, since the client sends
+/// the message again.
+async fn rename_all(files: &[PathBuf], name: &str) -> std::io::Result<()> {
+    let mut delivered = Vec::new();
+    let result = async {
+        for file in files {
+            if let Some(mailbox) = file.parent().and_then(Path::parent) {
+                let new = mailbox.join("new").join(name);
+                tokio::fs::rename(file, &new).await?;
+                delivered.push(new);
+            }
+        }
+        for file in &delivered {
+            if let Some(directory) = file.parent() {
+                tokio::fs::File::open(directory).await?.sync_all().await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        for file in delivered {
+            let _ = tokio::fs::remove_file(file).await;
+        }
+    }
+    result
+}
+
+/// Create the directories of a Maildir if they are missing, and those of its parent if it is a Maildir++ folder.
+async fn create_maildir(mailbox: &Path, root: &Path) -> std::io::Result<()> {
+    let parent = mailbox.parent().filter(|parent| *parent != root);
+    if let Some(parent) = parent {
+        for directory in &["tmp", "new", "cur"] {
+            tokio::fs::create_dir_all(parent.join(directory)).await?;
+        }
+    }
+    for directory in &["tmp", "new", "cur"] {
+        tokio::fs::create_dir_all(mailbox.join(directory)).await?;
+    }
+    if parent.is_some() {
+        tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(mailbox.join("maildirfolder"))
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message;
+    use crate::session::{Envelope, Peer};
+
+    fn address(local_part: &str) -> Address {
+        Address {
+            local_part: local_part.to_string(),
+            domain: "example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_mailbox() {
+        let maildir = Maildir::new("/var/mail", "mx.example.com");
+        assert_eq!(
+            maildir.mailbox(&address("Alice")),
+            Some(PathBuf::from("/var/mail/alice"))
+        );
+        assert_eq!(
+            maildir.mailbox(&address("alice+Lists")),
+            Some(PathBuf::from("/var/mail/alice/.lists"))
+        );
+        assert_eq!(
+            maildir.mailbox(&address("alice+")),
+            Some(PathBuf::from("/var/mail/alice"))
+        );
+        assert_eq!(maildir.mailbox(&address("../etc")), None);
+        assert_eq!(maildir.mailbox(&address("..")), None);
+        assert_eq!(maildir.mailbox(&address("+lists")), None);
+        assert_eq!(maildir.mailbox(&address("alice+a/b")), None);
+        let maildir =
+            maildir.local_parts(LocalPartRules::new().separators(&['-']).fold_case(false));
+        assert_eq!(
+            maildir.mailbox(&address("Alice-lists")),
+            Some(PathBuf::from("/var/mail/Alice/.lists"))
+        );
+        assert_eq!(
+            maildir.mailbox(&address("alice+lists")),
+            Some(PathBuf::from("/var/mail/alice+lists"))
+        );
+        assert_ne!(maildir.unique_name(), maildir.unique_name());
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_maildir() {
+        let root = std::env::temp_dir().join(format!("smtp-server-maildir-{}", std::process::id()));
+        let maildir = Maildir::new(&root, "mx.example.com");
+        let mut session = Session::new(Peer::Tcp("192.0.2.1:4321".parse().unwrap()), "test");
+        session.envelope = Some(Envelope {
+            recipients: vec![address("alice"), address("bob+lists"), address("Alice")],
+            ..Envelope::default()
+        });
+
+        let (mut writer, mut reader) = message::channel();
+        let delivery = async {
+            writer.write(b"Subject: test\r\n\r\nHello\r\n").await;
+            drop(writer);
+        };
+        let (result, ()) =
+            futures::join!(maildir.on_message_stream(&session, &mut reader), delivery);
+        result.unwrap();
+
+        let messages = |path: PathBuf| {
+            std::fs::read_dir(path)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<Vec<_>>()
+        };
+        let alice = messages(root.join("alice/new"));
+        assert_eq!(alice.len(), 1);
+        assert_eq!(
+            std::fs::read(&alice[0]).unwrap(),
+            b"Subject: test\r\n\r\nHello\r\n"
+        );
+        assert_eq!(messages(root.join("bob/.lists/new")).len(), 1);
+        assert!(root.join("bob/.lists/maildirfolder").exists());
+        assert!(root.join("bob/cur").is_dir());
+        assert!(messages(root.join("alice/tmp")).is_empty());
+
+        // a truncated message is not delivered
+        let (mut writer, mut reader) = message::channel();
+        let delivery = async {
+            writer.write(b"Subject: test\r\n").await;
+            writer.fail("connection closed").await;
+        };
+        let (result, ()) =
+            futures::join!(maildir.on_message_stream(&session, &mut reader), delivery);
+        assert!(result.is_err());
+        assert_eq!(messages(root.join("alice/new")).len(), 1);
+        assert!(messages(root.join("alice/tmp")).is_empty());
+
+        // a message that cannot be moved to all the mailboxes is removed from those it reached
+        let name = maildir.unique_name();
+        let files = vec![
+            root.join("alice/tmp").join(&name),
+            root.join("bob/tmp").join(&name),
+        ];
+        std::fs::write(&files[0], b"Subject: test\r\n\r\n").unwrap();
+        assert!(rename_all(&files, &name).await.is_err());
+        assert!(!root.join("alice/new").join(&name).exists());
+        assert_eq!(messages(root.join("alice/new")).len(), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+use super::{is_file_name, storage_error};
+use crate::{
+    commands::LocalPartRules,
+    error::SessionError,
+    events::EventHandler,
+    received::format_asctime,
+    session::{Address, Session},
+};
+use async_trait::async_trait;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Number of times a locked mailbox is tried before giving up.
+const LOCK_ATTEMPTS: usize = 100;
+/// Delay between two attempts to lock a mailbox.
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Age after which a `.lock` file is considered left by a crashed program.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(300);
+
+/// An [EventHandler] appending a copy of each message to the mbox file of each recipient, in the mboxrd format.
+///
+/// The mbox of `alice@example.com` and of `alice+lists@example.com` is `root/alice`. Local parts are compared in lowercase,
+/// unless other [rules](Mbox::local_parts) are set.
+/// The files are locked with `fcntl` and with a `.lock` file, like the programs reading them expect,
+/// and a message is appended to all the mailboxes of its recipients or to none of them.
+///
+/// ```no_run
+/// # use smtp_server::{delivery::Mbox, SmtpServer};
+/// let mut server = SmtpServer::new(Mbox::new("/var/mail"), "example.com");
+/// ```
+pub struct Mbox {
+    root: PathBuf,
+    local_parts: LocalPartRules,
+}
+
+impl Mbox {
+    /// Deliver to the mbox files in `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Mbox {
+        Mbox {
+            root: root.into(),
+            local_parts: LocalPartRules::default(),
+        }
+    }
+
+    /// Map the local parts to mbox files with these rules.
+    pub fn local_parts(mut self, rules: LocalPartRules) -> Self {
+        self.local_parts = rules;
+        self
+    }
+
+    /// The mbox file of a recipient, or `None` if its local part cannot be used as a file name.
+    pub fn mailbox(&self, recipient: &Address) -> Option<PathBuf> {
+        let user = recipient.mailbox(&self.local_parts).local_part;
+        match is_file_name(&user) && !user.ends_with(".lock") {
+            true => Some(self.root.join(user)),
+            false => None,
+        }
+    }
+}
+
+#[async_trait]
+impl EventHandler for Mbox {
+    // the whole message is received before locking the mailboxes, so that slow clients do not hold the locks
+    async fn on_message(&self, session: &Session, message: &[u8]) -> Result<(), SessionError> {
+        let envelope = match &session.envelope {
+            Some(envelope) => envelope,
+            None => return Err(SessionError::Handler("no recipients".to_string())),
+        };
+        let mut mailboxes = Vec::new();
+        for recipient in &envelope.recipients {
+            match self.mailbox(recipient) {
+                Some(mailbox) => mailboxes.push(mailbox),
+                None => {
+                    return Err(SessionError::Handler(format!(
+                        "no mailbox for {}",
+                        recipient
+                    )))
+                }
+            }
+        }
+        // always locked in the same order, so that concurrent deliveries cannot wait for each other
+        mailboxes.sort();
+        mailboxes.dedup();
+
+        let sender = match &envelope.reverse_path {
+            Some(reverse_path) => reverse_path.to_string().replace(char::is_whitespace, "_"),
+            None => "MAILER-DAEMON".to_string(),
+        };
+        let entry = mbox_entry(message, &sender, SystemTime::now());
+        let result = tokio::task::spawn_blocking(move || append_all(&mailboxes, &entry))
+            .await
+            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))
+            .and_then(|result| result);
+        result.map_err(|e| {
+            warn!("Delivery of {} to an mbox failed: {}", envelope.id, e);
+            storage_error(e)
+        })
+    }
+}
+
+/// A message in the mboxrd format: a `From ` line, the lines of the message ending with LF
+/// with a `>` added before those starting with `From ` after any number of `>`, and an empty line.
+fn mbox_entry(message: &[u8], sender: &str, date: SystemTime) -> Vec<u8> {
+    let mut entry = format!("From {} {}\n", sender, format_asctime(date)).into_bytes();
+    entry.reserve(message.len() + 1);
+
+    let message = message.strip_suffix(b"\n").unwrap_or(message);
+    for line in message.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let unquoted = &line[line.iter().take_while(|b| **b == b'>').count()..];
+        if unquoted.starts_with(b"From ") {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+    entry
+}
+
+/// Append an entry to all the mailboxes, or to none of them if one fails.
+fn append_all(mailboxes: &[PathBuf], entry: &[u8]) -> std::io::Result<()> {
+    let mut locked = Vec::with_capacity(mailboxes.len());
+    for mailbox in mailboxes {
+        locked.push(LockedMailbox::open(mailbox)?);
+    }
+
+    for i in 0..locked.len() {
+        if let Err(e) = locked[i].append(entry) {
+            for mailbox in &locked[..=i] {
+                mailbox.rollback();
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// An mbox file opened for appending, with its locks. The locks are released when it is dropped.
+struct LockedMailbox {
+    file: File,
+    /// The length of the file before anything was appended.
+    length: u64,
+    path: PathBuf,
+    _dotlock: DotLock,
+}
+
+impl LockedMailbox {
+    fn open(path: &Path) -> std::io::Result<LockedMailbox> {
+        let dotlock = DotLock::acquire(path)?;
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        lock_file(&file)?;
+        let length = file.metadata()?.len();
+        Ok(LockedMailbox {
+            file,
+            length,
+            path: path.to_path_buf(),
+            _dotlock: dotlock,
+        })
+    }
+
+    fn append(&mut self, entry: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(entry)?;
+        self.file.sync_data()
+    }
+
+    /// Remove what was appended to the file.
+    fn rollback(&self) {
+        if let Err(e) = self
+            .file
+            .set_len(self.length)
+            .and_then(|()| self.file.sync_data())
+        {
+            error!(
+                "Cannot remove a partial message from {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// The `.lock` file next to a mailbox, removed when dropped.
+struct DotLock {
+    path: PathBuf,
+}
+
+impl DotLock {
+    fn acquire(mailbox: &Path) -> std::io::Result<DotLock> {
+        let mut path = mailbox.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+
+        for _ in 0..LOCK_ATTEMPTS {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(DotLock { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let age = std::fs::metadata(&path)
+                        .and_then(|m| m.modified())
+                        .map(|m| m.elapsed().unwrap_or_default());
+                    if matches!(age, Ok(age) if age > STALE_LOCK_AGE) {
+                        warn!("Removing the stale lock {}", path.display());
+                        let _ = std::fs::remove_file(&path);
+                    } else {
+                        std::thread::sleep(LOCK_RETRY_DELAY);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            format!("{} is locked", mailbox.display()),
+        ))
+    }
+}
+
+impl Drop for DotLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            error!("Cannot remove the lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Take the `fcntl` write lock of a whole file. It is released when the file is closed.
+fn lock_file(file: &File) -> std::io::Result<()> {
+    // SAFETY: all-zero bytes are a valid `flock` structure
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+
+    for _ in 0..LOCK_ATTEMPTS {
+        // SAFETY: the file descriptor is open while `file` is borrowed, and `lock` outlives the call
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+            return Ok(());
+        }
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EAGAIN) => std::thread::sleep(LOCK_RETRY_DELAY),
+            Some(libc::EINTR) => (),
+            _ => return Err(e),
+        }
+    }
+    Err(std::io::Error::new(
+        ErrorKind::TimedOut,
+        "the mailbox is locked",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::{Envelope, Peer};
+    use std::time::UNIX_EPOCH;
+
+    fn address(local_part: &str) -> Address {
+        Address {
+            local_part: local_part.to_string(),
+            domain: "example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_mbox_entry() {
+        let date = UNIX_EPOCH + Duration::from_secs(1_792_404_000);
+        assert_eq!(
+            mbox_entry(b"Subject: test\r\n\r\nFrom here\r\n>From there\r\nFromage\r\n", "alice@example.com", date),
+            b"From alice@example.com Mon Oct 19 10:00:00 2026\nSubject: test\n\n>From here\n>>From there\nFromage\n\n".to_vec()
+        );
+        assert_eq!(
+            mbox_entry(b"no final line break", "MAILER-DAEMON", date),
+            b"From MAILER-DAEMON Mon Oct 19 10:00:00 2026\nno final line break\n\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_mailbox() {
+        let mbox = Mbox::new("/var/mail");
+        assert_eq!(
+            mbox.mailbox(&address("Alice+lists")),
+            Some(PathBuf::from("/var/mail/alice"))
+        );
+        assert_eq!(mbox.mailbox(&address("alice.lock")), None);
+        assert_eq!(mbox.mailbox(&address("../alice")), None);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_mbox() {
+        let root = std::env::temp_dir().join(format!("smtp-server-mbox-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mbox = Mbox::new(&root);
+        let mut session = Session::new(Peer::Tcp("192.0.2.1:4321".parse().unwrap()), "test");
+        session.envelope = Some(Envelope {
+            recipients: vec![address("alice"), address("bob"), address("alice+lists")],
+            ..Envelope::default()
+        });
+
+        mbox.on_message(&session, b"Subject: first\r\n\r\nFrom me\r\n")
+            .await
+            .unwrap();
+        mbox.on_message(&session, b"Subject: second\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+
+        let alice = String::from_utf8(std::fs::read(root.join("alice")).unwrap()).unwrap();
+        let entries: Vec<&str> = alice.split("\n\nFrom ").collect();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].starts_with("From MAILER-DAEMON "));
+        assert!(entries[0].ends_with("\nSubject: first\n\n>From me"));
+        assert!(entries[1].ends_with("\nSubject: second\n\nHello\n\n"));
+        assert_eq!(std::fs::read(root.join("bob")).unwrap(), alice.as_bytes());
+        assert!(!root.join("alice.lock").exists());
+
+        session.envelope = Some(Envelope {
+            recipients: vec![address("../alice")],
+            ..Envelope::default()
+        });
+        assert!(mbox
+            .on_message(&session, b"Subject: refused\r\n\r\n")
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{
+    commands::{LocalPart, LocalPartRules},
+    error::SessionError,
+    events::EventHandler,
+    session::{Address, Envelope, Session},
+};
+use async_trait::async_trait;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+/// Time a command may run by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+/// Number of bytes of the error output of a command kept for the logs.
+const MAX_STDERR: u64 = 4096;
+
+/// An [EventHandler] running a command for each recipient, with the message on its standard input.
+///
+/// The command gets the envelope in its environment: `SENDER` (empty for bounces), `RECIPIENT`, `DOMAIN`,
+/// `LOCAL` (the local part of the recipient), and `USER` and `EXTENSION` (the local part before and after a `+`,
+/// normalized with the [rules](Pipe::local_parts) of the handler).
+///
+/// Its exit status is interpreted like `sysexits.h`: 0 accepts the message, `EX_OSERR` and `EX_TEMPFAIL`
+/// refuse it temporarily, and the other statuses of `sysexits.h` refuse it permanently.
+/// A command killed by a signal, still running after the timeout, or exiting with another status refuses it temporarily.
+/// The server accepts one recipient per transaction, so that the client sends the message again only to the
+/// recipients the command failed for. The mailboxes an [alias](crate::SmtpServer::aliases) leads to are delivered
+/// in the same transaction: if the command fails for one of them, the message is refused and the others may get
+/// it again. Only the first 4 KiB of the error output of the command are logged.
+///
+/// ```no_run
+/// # use smtp_server::{delivery::Pipe, SmtpServer};
+/// # use std::time::Duration;
+/// let pipe = Pipe::new("/usr/local/bin/ticket").arg("--queue=support").timeout(Duration::from_secs(60));
+/// let mut server = SmtpServer::new(pipe, "example.com");
+/// ```
+pub struct Pipe {
+    program: PathBuf,
+    args: Vec<String>,
+    timeout: Duration,
+    local_parts: LocalPartRules,
+}
+
+impl Pipe {
+    pub fn new(program: impl Into<PathBuf>) -> Pipe {
+        Pipe {
+            program: program.into(),
+            args: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            local_parts: LocalPartRules::default(),
+        }
+    }
+
+    /// Add an argument to the command.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Kill the command and refuse the message temporarily if it runs longer (5 minutes by default).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Split the local parts into `USER` and `EXTENSION` with these rules.
+    pub fn local_parts(mut self, rules: LocalPartRules) -> Self {
+        self.local_parts = rules;
+        self
+    }
+
+    /// Run the command for a recipient.
+    async fn run(
+        &self,
+        envelope: &Envelope,
+        recipient: &Address,
+        message: &[u8],
+    ) -> Result<(), SessionError> {
+        let (user, extension) =
+            LocalPart::from_unquoted(&recipient.local_part).split_detail(&self.local_parts);
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .env(
+                "SENDER",
+                envelope
+                    .reverse_path
+                    .as_ref()
+                    .map(Address::to_string)
+                    .unwrap_or_default(),
+            )
+            .env("RECIPIENT", recipient.to_string())
+            .env("DOMAIN", &recipient.domain)
+            .env("LOCAL", &recipient.local_part)
+            .env("USER", user)
+            .env("EXTENSION", extension.unwrap_or_default())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                SessionError::Handler(format!("cannot run {}: {}", self.program.display(), e))
+            })?;
+
+        let mut stdin = child.stdin.take();
+        let writing = async move {
+            if let Some(stdin) = &mut stdin {
+                match stdin.write_all(message).await {
+                    // the command may exit without reading the whole message
+                    Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                        warn!("Cannot write to {:?}: {}", stdin, e)
+                    }
+                    _ => (),
+                }
+            }
+        };
+        let mut stderr = child.stderr.take();
+        let reading = async move {
+            let mut kept = Vec::new();
+            if let Some(stderr) = &mut stderr {
+                let _ = stderr.take(MAX_STDERR).read_to_end(&mut kept).await;
+                // the rest is read so that the command does not block on a full pipe
+                let _ = tokio::io::copy(stderr, &mut tokio::io::sink()).await;
+            }
+            kept
+        };
+        let running = async {
+            let ((), stderr, status) = futures::join!(writing, reading, child.wait());
+            status.map(|status| (status, stderr))
+        };
+        let (status, stderr) = match tokio::time::timeout(self.timeout, running).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => {
+                return Err(SessionError::Handler(format!(
+                    "cannot run {}: {}",
+                    self.program.display(),
+                    e
+                )))
+            }
+            Err(_) => {
+                warn!(
+                    "{} timed out delivering to {}",
+                    self.program.display(),
+                    recipient
+                );
+                return Err(SessionError::Handler("delivery timed out".to_string()));
+            }
+        };
+
+        if !status.success() {
+            warn!(
+                "{} failed delivering to {} with {}: {}",
+                self.program.display(),
+                recipient,
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            );
+        }
+        exit_status_result(status.code())
+    }
+}
+
+#[async_trait]
+impl EventHandler for Pipe {
+    async fn on_message(&self, session: &Session, message: &[u8]) -> Result<(), SessionError> {
+        let envelope = match &session.envelope {
+            Some(envelope) => envelope,
+            None => return Err(SessionError::Handler("no recipients".to_string())),
+        };
+
+        let mut result = Ok(());
+        for recipient in &envelope.recipients {
+            match (self.run(envelope, recipient, message).await, &result) {
+                (Ok(()), _) => (),
+                // a temporary failure is reported rather than a permanent one, so that the client tries again
+                (Err(e), Ok(()))
+                | (Err(e @ SessionError::Handler(_)), Err(SessionError::Rejected(_))) => {
+                    result = Err(e)
+                }
+                (Err(_), Err(_)) => (),
+            }
+        }
+        result
+    }
+
+    fn max_recipients(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// The result of a delivery according to the exit status of the command, as defined by `sysexits.h`.
+fn exit_status_result(code: Option<i32>) -> Result<(), SessionError> {
+    let (permanent, description) = match code {
+        Some(0) => return Ok(()),
+        Some(64) => (true, "command line usage error"),
+        Some(65) => (true, "data format error"),
+        Some(66) => (true, "cannot open input"),
+        Some(67) => (true, "addressee unknown"),
+        Some(68) => (true, "host name unknown"),
+        Some(69) => (true, "service unavailable"),
+        Some(70) => (true, "internal software error"),
+        Some(71) => (false, "system error"),
+        Some(72) => (true, "critical OS file missing"),
+        Some(73) => (true, "cannot create output file"),
+        Some(74) => (true, "input/output error"),
+        Some(75) => (false, "temporary failure"),
+        Some(76) => (true, "remote error in protocol"),
+        Some(77) => (true, "permission denied"),
+        Some(78) => (true, "configuration error"),
+        Some(_) => (false, "unknown error"),
+        None => (false, "delivery interrupted"),
+    };
+    match permanent {
+        true => Err(SessionError::Rejected(description.to_string())),
+        false => Err(SessionError::Handler(description.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::Peer;
+
+    fn session(recipients: &[&str]) -> Session {
+        let mut session = Session::new(Peer::Tcp("192.0.2.1:4321".parse().unwrap()), "test");
+        session.envelope = Some(Envelope {
+            reverse_path: Some(Address {
+                local_part: "alice".to_string(),
+                domain: "example.com".to_string(),
+            }),
+            recipients: recipients
+                .iter()
+                .map(|local_part| Address {
+                    local_part: local_part.to_string(),
+                    domain: "example.com".to_string(),
+                })
+                .collect(),
+            ..Envelope::default()
+        });
+        session
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert!(exit_status_result(Some(0)).is_ok());
+        assert!(matches!(
+            exit_status_result(Some(67)),
+            Err(SessionError::Rejected(_))
+        ));
+        assert!(matches!(
+            exit_status_result(Some(75)),
+            Err(SessionError::Handler(_))
+        ));
+        assert!(matches!(
+            exit_status_result(Some(1)),
+            Err(SessionError::Handler(_))
+        ));
+        assert!(matches!(
+            exit_status_result(None),
+            Err(SessionError::Handler(_))
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_pipe() {
+        let directory =
+            std::env::temp_dir().join(format!("smtp-server-pipe-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let pipe = Pipe::new("sh")
+            .arg("-c")
+            .arg(r#"test "$SENDER" = alice@example.com && cat > "$0/$USER-$EXTENSION""#)
+            .arg(directory.to_str().unwrap());
+        pipe.on_message(
+            &session(&["bob+tickets", "carol"]),
+            b"Subject: test\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(directory.join("bob-tickets")).unwrap(),
+            b"Subject: test\r\n\r\n"
+        );
+        assert_eq!(
+            std::fs::read(directory.join("carol-")).unwrap(),
+            b"Subject: test\r\n\r\n"
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+
+        let pipe = Pipe::new("sh")
+            .arg("-c")
+            .arg("case $USER in temporary) exit 75;; *) exit 67;; esac");
+        let message = b"Subject: test\r\n\r\n";
+        assert!(matches!(
+            pipe.on_message(&session(&["unknown"]), message).await,
+            Err(SessionError::Rejected(_))
+        ));
+        assert!(matches!(
+            pipe.on_message(&session(&["unknown", "temporary"]), message)
+                .await,
+            Err(SessionError::Handler(_))
+        ));
+
+        // the error output is logged, but only its beginning is kept
+        let pipe = Pipe::new("sh")
+            .arg("-c")
+            .arg("head -c 1000000 /dev/zero >&2; exit 75");
+        assert!(matches!(
+            pipe.on_message(&session(&["bob"]), message).await,
+            Err(SessionError::Handler(_))
+        ));
+        assert_eq!(pipe.max_recipients(), Some(1));
+
+        let pipe = Pipe::new("sh")
+            .arg("-c")
+            .arg("sleep 10")
+            .timeout(Duration::from_millis(100));
+        assert!(matches!(
+            pipe.on_message(&session(&["bob"]), message).await,
+            Err(SessionError::Handler(_))
+        ));
+
+        let pipe = Pipe::new("/nonexistent/command");
+        assert!(matches!(
+            pipe.on_message(&session(&["bob"]), message).await,
+            Err(SessionError::Handler(_))
+        ));
+    }
+}
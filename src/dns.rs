@@ -0,0 +1,438 @@
+use async_trait::async_trait;
+use futures::channel::oneshot;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use trust_dns_resolver::error::{ResolveErrorKind, ResolveResult};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// The DNS lookups the server makes about its clients.
+/// Lookups that find no record succeed with an empty list, errors are for failures to get an answer.
+///
+/// The default is [SystemResolver]. Another implementation can be set with [SmtpServer::resolver](crate::SmtpServer::resolver),
+/// for example to use a local cache or to run without network in tests.
+/// The lookups delay the greeting of the clients, so they should fail rather than take more than a few seconds.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// The hostnames an address points to (PTR records).
+    async fn reverse_lookup(&self, ip: IpAddr) -> std::io::Result<Vec<String>>;
+
+    /// The addresses of a hostname (A and AAAA records).
+    async fn lookup_ip(&self, hostname: &str) -> std::io::Result<Vec<IpAddr>>;
+}
+
+/// Time after which a lookup of the [SystemResolver], of a blocklist, or the confirmation of a hostname fails.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A [Resolver] using the configuration of the system (`/etc/resolv.conf` on Unix).
+/// Lookups run concurrently on a background thread, and fail if they take more than 5 seconds.
+/// The resolver is created on first use. If the configuration cannot be read, all the lookups fail.
+#[derive(Default)]
+pub struct SystemResolver {
+    background: Mutex<Option<Result<Background, String>>>,
+}
+
+/// The resolver and the runtime running its lookups on a background thread, which stops when this is dropped.
+struct Background {
+    resolver: TokioAsyncResolver,
+    runtime: tokio02::runtime::Handle,
+    _stop: oneshot::Sender<()>,
+}
+
+impl Background {
+    fn start() -> std::io::Result<Background> {
+        let mut runtime = tokio02::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        let resolver = runtime.block_on(TokioAsyncResolver::from_system_conf(handle.clone()))?;
+        let (stop, stopped) = oneshot::channel::<()>();
+        std::thread::Builder::new()
+            .name("dns-resolver".to_string())
+            .spawn(move || {
+                runtime.block_on(async {
+                    let _ = stopped.await;
+                })
+            })?;
+        Ok(Background {
+            resolver,
+            runtime: handle,
+            _stop: stop,
+        })
+    }
+}
+
+impl SystemResolver {
+    pub fn new() -> SystemResolver {
+        SystemResolver::default()
+    }
+
+    /// Run a lookup with the resolver, created on first use.
+    async fn lookup<T, F, L>(&self, lookup: F) -> std::io::Result<Vec<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(TokioAsyncResolver) -> L,
+        L: Future<Output = ResolveResult<Vec<T>>> + Send + 'static,
+    {
+        let (resolver, runtime) = {
+            let mut background = self.background.lock().unwrap_or_else(|p| p.into_inner());
+            let background = background.get_or_insert_with(|| {
+                Background::start().map_err(|e| {
+                    error!("Cannot create the DNS resolver: {}", e);
+                    e.to_string()
+                })
+            });
+            match background {
+                Ok(background) => (background.resolver.clone(), background.runtime.clone()),
+                Err(e) => return Err(std::io::Error::new(ErrorKind::Other, e.clone())),
+            }
+        };
+
+        let lookup = lookup(resolver);
+        let result = runtime
+            .spawn(async move { tokio02::time::timeout(LOOKUP_TIMEOUT, lookup).await })
+            .await
+            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
+        match result {
+            Ok(Ok(records)) => Ok(records),
+            Ok(Err(e)) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                Ok(Vec::new())
+            }
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "DNS lookup timed out",
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn reverse_lookup(&self, ip: IpAddr) -> std::io::Result<Vec<String>> {
+        self.lookup(move |resolver| async move {
+            let names = resolver.reverse_lookup(ip).await?;
+            Ok(names
+                .iter()
+                .map(|name| name.to_utf8().trim_end_matches('.').to_string())
+                .collect())
+        })
+        .await
+    }
+
+    async fn lookup_ip(&self, hostname: &str) -> std::io::Result<Vec<IpAddr>> {
+        let hostname = hostname.to_string();
+        self.lookup(move |resolver| async move {
+            Ok(resolver
+                .lookup_ip(hostname.as_str())
+                .await?
+                .iter()
+                .collect())
+        })
+        .await
+    }
+}
+
+/// Maximum number of hostnames of an address checked by [confirmed_hostname].
+const MAX_HOSTNAMES: usize = 10;
+
+/// The hostname of an address, if it points back to the address (forward-confirmed reverse DNS).
+/// Only the first 10 hostnames are checked, and the whole confirmation fails after 5 seconds.
+pub async fn confirmed_hostname(resolver: &dyn Resolver, ip: IpAddr) -> Option<String> {
+    match tokio::time::timeout(LOOKUP_TIMEOUT, confirm_hostname(resolver, ip)).await {
+        Ok(hostname) => hostname,
+        Err(_) => {
+            debug!("Reverse DNS confirmation of {} timed out", ip);
+            None
+        }
+    }
+}
+
+async fn confirm_hostname(resolver: &dyn Resolver, ip: IpAddr) -> Option<String> {
+    let hostnames = match resolver.reverse_lookup(ip).await {
+        Ok(hostnames) => hostnames,
+        Err(e) => {
+            debug!("Reverse lookup of {} failed: {}", ip, e);
+            return None;
+        }
+    };
+    for hostname in hostnames.into_iter().take(MAX_HOSTNAMES) {
+        match resolver.lookup_ip(&hostname).await {
+            Ok(addresses) if addresses.contains(&ip) => return Some(hostname),
+            Ok(_) => (),
+            Err(e) => debug!("Lookup of {} failed: {}", hostname, e),
+        }
+    }
+    None
+}
+
+/// DNS blocklists (DNSBL) like `zen.spamhaus.org`, where the addresses of the clients are looked up when they connect.
+///
+/// Each list listing the client adds its weight to the score of the client, which is given to the
+/// [EventHandler](crate::EventHandler) in the [session](crate::session::Session::blocklist_score).
+/// The clients reaching the threshold, if any, get `554` naming the lists and are disconnected.
+/// Lookups that fail are ignored, and a list not answering within 5 seconds does not list the client.
+///
+/// ```no_run
+/// # use smtp_server::{dns::Blocklists, EventHandler, SmtpServer};
+/// # fn example(handler: impl EventHandler + 'static) {
+/// SmtpServer::new(handler, "mail.example.com")
+///     .blocklists(Blocklists::new().list("zen.spamhaus.org", 10).list("bl.spamcop.net", 5).threshold(10));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Blocklists {
+    lists: Vec<(String, u32)>,
+    threshold: Option<u32>,
+}
+
+impl Blocklists {
+    pub fn new() -> Blocklists {
+        Blocklists::default()
+    }
+
+    /// Look the clients up in the list of this DNS zone, which adds `weight` to the score of the clients it lists.
+    pub fn list(mut self, zone: &str, weight: u32) -> Self {
+        self.lists
+            .push((zone.trim_end_matches('.').to_ascii_lowercase(), weight));
+        self
+    }
+
+    /// Refuse the clients whose score is at least `threshold`.
+    pub fn threshold(mut self, threshold: u32) -> Self {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    /// The zones of the lists listing an address.
+    pub(crate) async fn lookup(&self, resolver: &dyn Resolver, ip: IpAddr) -> Vec<String> {
+        let lookups = self.lists.iter().map(|(zone, _)| async move {
+            let name = format!("{}.{}", reversed_name(ip), zone);
+            let result = tokio::time::timeout(LOOKUP_TIMEOUT, resolver.lookup_ip(&name))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        ErrorKind::TimedOut,
+                        "DNS lookup timed out",
+                    ))
+                });
+            match result {
+                Ok(addresses) => addresses
+                    .iter()
+                    .any(is_listing_answer)
+                    .then(|| zone.clone()),
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    warn!(
+                        "Lookup of {} timed out, {} is not listed in {}",
+                        name, ip, zone
+                    );
+                    None
+                }
+                Err(e) => {
+                    debug!("Lookup of {} failed: {}", name, e);
+                    None
+                }
+            }
+        });
+        futures::future::join_all(lookups)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// The sum of the weights of these lists.
+    pub(crate) fn score(&self, listed: &[String]) -> u32 {
+        self.lists
+            .iter()
+            .filter(|(zone, _)| listed.contains(zone))
+            .map(|(_, weight)| weight)
+            .sum()
+    }
+
+    /// Whether the clients with this score are refused.
+    pub(crate) fn rejects(&self, score: u32) -> bool {
+        self.threshold.map_or(false, |threshold| score >= threshold)
+    }
+}
+
+/// The name an address is looked up with in a DNS list (RFC 5782 section 2): the bytes of an IPv4 address
+/// and the nibbles of an IPv6 address in reverse order.
+fn reversed_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .map(u8::to_string)
+            .collect::<Vec<_>>()
+            .join("."),
+        IpAddr::V6(ip) => {
+            let nibbles = ip
+                .octets()
+                .iter()
+                .flat_map(|byte| [byte >> 4, byte & 0xF])
+                .collect::<Vec<_>>();
+            nibbles
+                .iter()
+                .rev()
+                .map(|nibble| format!("{:x}", nibble))
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+    }
+}
+
+/// Whether an answer of a DNS list means that the address is listed. Lists answer with `127.0.0.2` to `127.0.0.255`
+/// (RFC 5782 section 2.1); other answers, like `127.255.255.0/24` reporting an error such as a refused resolver,
+/// do not list the address.
+fn is_listing_answer(answer: &IpAddr) -> bool {
+    match answer {
+        IpAddr::V4(ip) => ip.octets()[..3] == [127, 0, 0] && ip.octets()[3] >= 2,
+        IpAddr::V6(_) => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A resolver answering from fixed records.
+    #[derive(Default)]
+    pub(crate) struct StaticResolver {
+        pub(crate) names: HashMap<IpAddr, Vec<String>>,
+        pub(crate) addresses: HashMap<String, Vec<IpAddr>>,
+        /// Time taken by each lookup of an address.
+        pub(crate) delay: Duration,
+    }
+
+    #[async_trait]
+    impl Resolver for StaticResolver {
+        async fn reverse_lookup(&self, ip: IpAddr) -> std::io::Result<Vec<String>> {
+            Ok(self.names.get(&ip).cloned().unwrap_or_default())
+        }
+
+        async fn lookup_ip(&self, hostname: &str) -> std::io::Result<Vec<IpAddr>> {
+            tokio::time::sleep(self.delay).await;
+            Ok(self.addresses.get(hostname).cloned().unwrap_or_default())
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the DNS configuration of the host"]
+    async fn test_system_resolver() {
+        let resolver = SystemResolver::new();
+        let (first, second) = futures::join!(
+            resolver.lookup_ip("localhost"),
+            resolver.lookup_ip("localhost")
+        );
+        assert!(first.unwrap().contains(&"127.0.0.1".parse().unwrap()));
+        assert!(!second.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_confirmed_hostname() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut resolver = StaticResolver::default();
+        resolver.names.insert(
+            ip,
+            vec![
+                "spoofed.example.net".to_string(),
+                "mail.example.net".to_string(),
+            ],
+        );
+        resolver.addresses.insert(
+            "spoofed.example.net".to_string(),
+            vec!["198.51.100.1".parse().unwrap()],
+        );
+        resolver
+            .addresses
+            .insert("mail.example.net".to_string(), vec![ip]);
+
+        assert_eq!(
+            confirmed_hostname(&resolver, ip).await,
+            Some("mail.example.net".to_string())
+        );
+        assert_eq!(
+            confirmed_hostname(&resolver, "192.0.2.2".parse().unwrap()).await,
+            None
+        );
+
+        // a client cannot delay its greeting with many hostnames
+        let many: Vec<String> = (0..20).map(|i| format!("host{}.example.net", i)).collect();
+        resolver.names.insert(ip, many);
+        resolver
+            .addresses
+            .insert("host10.example.net".to_string(), vec![ip]);
+        assert_eq!(confirmed_hostname(&resolver, ip).await, None);
+
+        tokio::time::pause();
+        resolver.delay = Duration::from_secs(2);
+        let start = tokio::time::Instant::now();
+        assert_eq!(confirmed_hostname(&resolver, ip).await, None);
+        assert!(start.elapsed() < LOOKUP_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_blocklists() {
+        assert_eq!(reversed_name("192.0.2.1".parse().unwrap()), "1.2.0.192");
+        assert_eq!(
+            reversed_name("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2"
+        );
+
+        let mut resolver = StaticResolver::default();
+        resolver.addresses.insert(
+            "1.2.0.192.zen.example.org".to_string(),
+            vec!["127.0.0.2".parse().unwrap()],
+        );
+        resolver.addresses.insert(
+            "1.2.0.192.bl.example.net".to_string(),
+            vec!["127.0.0.4".parse().unwrap()],
+        );
+        resolver.addresses.insert(
+            "1.2.0.192.broken.example.com".to_string(),
+            vec!["127.255.255.254".parse().unwrap()],
+        );
+        resolver.addresses.insert(
+            "1.2.0.192.other.example.com".to_string(),
+            vec!["127.0.0.1".parse().unwrap()],
+        );
+        resolver.addresses.insert(
+            "2.2.0.192.bl.example.net".to_string(),
+            vec!["127.0.0.2".parse().unwrap()],
+        );
+        let blocklists = Blocklists::new()
+            .list("zen.example.org.", 10)
+            .list("BL.example.net", 3)
+            .list("broken.example.com", 10)
+            .list("other.example.com", 10)
+            .threshold(10);
+
+        let listed = blocklists
+            .lookup(&resolver, "192.0.2.1".parse().unwrap())
+            .await;
+        assert_eq!(listed, ["zen.example.org", "bl.example.net"]);
+        assert_eq!(blocklists.score(&listed), 13);
+        assert!(blocklists.rejects(13));
+
+        let listed = blocklists
+            .lookup(&resolver, "192.0.2.2".parse().unwrap())
+            .await;
+        assert_eq!(listed, ["bl.example.net"]);
+        assert!(!blocklists.rejects(blocklists.score(&listed)));
+        assert!(blocklists
+            .lookup(&resolver, "192.0.2.3".parse().unwrap())
+            .await
+            .is_empty());
+        assert!(!Blocklists::new().rejects(100));
+    }
+}
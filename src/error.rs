@@ -0,0 +1,295 @@
+use crate::replies::Reply;
+
+/// An error preventing the server from starting.
+#[derive(Debug)]
+pub enum ServerError {
+    /// A listener address could not be resolved.
+    Resolve {
+        address: String,
+        error: std::io::Error,
+    },
+    /// A listener could not be bound to its address.
+    Bind {
+        address: String,
+        error: std::io::Error,
+    },
+    /// A listener uses implicit TLS but the server has no certificate.
+    MissingCertificate { listener: String },
+    /// A TCP address was added to a unix socket listener.
+    TcpAddressOnUnixSocket { listener: String, address: String },
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Resolve { address, error } => {
+                write!(f, "failed to resolve {}: {}", address, error)
+            }
+            ServerError::Bind { address, error } => {
+                write!(f, "failed to bind {}: {}", address, error)
+            }
+            ServerError::MissingCertificate { listener } => {
+                write!(
+                    f,
+                    "listener {} uses implicit TLS but no certificate is configured",
+                    listener
+                )
+            }
+            ServerError::TcpAddressOnUnixSocket { listener, address } => {
+                write!(
+                    f,
+                    "listener {} is a unix socket and cannot bind {}",
+                    listener, address
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Resolve { error, .. } => Some(error),
+            ServerError::Bind { error, .. } => Some(error),
+            ServerError::MissingCertificate { .. } => None,
+            ServerError::TcpAddressOnUnixSocket { .. } => None,
+        }
+    }
+}
+
+/// An error loading an [alias table](crate::aliases::AliasTable).
+#[derive(Debug)]
+pub enum AliasError {
+    /// The file could not be read.
+    Io {
+        path: std::path::PathBuf,
+        error: std::io::Error,
+    },
+    /// A line of the table is invalid.
+    Syntax { line: usize, message: String },
+}
+
+impl std::fmt::Display for AliasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AliasError::Io { path, error } => {
+                write!(f, "failed to read {}: {}", path.display(), error)
+            }
+            AliasError::Syntax { line, message } => {
+                write!(f, "invalid alias on line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AliasError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AliasError::Io { error, .. } => Some(error),
+            AliasError::Syntax { .. } => None,
+        }
+    }
+}
+
+/// An error loading a TLS [certificate](crate::tls::Certificate).
+#[derive(Debug)]
+pub enum TlsError {
+    /// A file could not be read.
+    Io {
+        path: std::path::PathBuf,
+        error: std::io::Error,
+    },
+    /// A PEM certificate chain or private key could not be parsed.
+    InvalidPem(String),
+    /// The private key does not belong to the certificate.
+    KeyMismatch,
+    /// The TLS library refused the certificate, or the password of the archive is wrong.
+    InvalidIdentity(crate::tls::LibraryError),
+    /// The TLS library of the enabled feature cannot do this.
+    Unsupported(&'static str),
+    /// The [TLS settings](crate::tls::TlsSettings) cannot be satisfied.
+    InvalidSettings(String),
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io { path, error } => {
+                write!(f, "failed to read {}: {}", path.display(), error)
+            }
+            TlsError::InvalidPem(e) => write!(f, "invalid PEM file: {}", e),
+            TlsError::KeyMismatch => write!(f, "the private key does not match the certificate"),
+            TlsError::InvalidIdentity(e) => write!(f, "invalid certificate: {}", e),
+            TlsError::Unsupported(e) => write!(f, "unsupported: {}", e),
+            TlsError::InvalidSettings(e) => write!(f, "invalid TLS settings: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Io { error, .. } => Some(error),
+            TlsError::InvalidIdentity(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// An error occurring while serving a client.
+///
+/// Some errors are reported to the client with a [reply](SessionError::reply) and the session goes on,
+/// the others end the session.
+#[derive(Debug)]
+pub enum SessionError {
+    /// Reading from or writing to the client failed.
+    Io(std::io::Error),
+    /// The TLS handshake failed.
+    Tls(crate::tls::LibraryError),
+    /// The client disconnected in the middle of a transfer.
+    ConnectionClosed,
+    /// The client sent a line longer than allowed.
+    LineTooLong,
+    /// The client sent bytes that are not valid UTF-8.
+    InvalidUtf8,
+    /// The client sent something that is not a valid command.
+    Syntax(String),
+    /// The command is not allowed at this point of the session.
+    BadSequence(&'static str),
+    /// The message could not be parsed.
+    InvalidMessage(String),
+    /// The message is bigger than the limit of the listener.
+    MessageTooLarge,
+    /// The event handler refused the message.
+    Handler(String),
+    /// The event handler refused the message permanently.
+    Rejected(String),
+    /// The message could not be stored because the disk or a quota is full.
+    InsufficientStorage,
+    /// The client cancelled the authentication exchange.
+    AuthenticationCancelled,
+    /// The client answered the authentication exchange with something that is not base64.
+    InvalidAuthResponse,
+    /// The credentials of the client were refused.
+    AuthenticationFailed,
+    /// The client may not send messages to the domain of the recipient.
+    RelayDenied,
+    /// The aliases of a recipient cannot be resolved.
+    Alias(crate::aliases::ResolveError),
+    /// The transaction has as many recipients as the event handler accepts.
+    TooManyRecipients,
+    /// The client made too many errors and was disconnected.
+    TooManyErrors,
+}
+
+impl SessionError {
+    /// The reply telling the client about this error, or `None` if the session cannot go on.
+    pub fn reply(&self) -> Option<Reply> {
+        match self {
+            SessionError::Io(_)
+            | SessionError::Tls(_)
+            | SessionError::ConnectionClosed
+            | SessionError::TooManyErrors => None,
+            SessionError::LineTooLong => {
+                Some(Reply::SyntaxError().with_message("5.5.2 Line too long".to_string()))
+            }
+            SessionError::InvalidUtf8 | SessionError::Syntax(_) => {
+                Some(Reply::SyntaxError().with_message("Unrecognized command".to_string()))
+            }
+            SessionError::BadSequence(message) => {
+                Some(Reply::BadSequenceOfCommands().with_message(message.to_string()))
+            }
+            SessionError::InvalidMessage(_) => Some(
+                Reply::TransactionFailed()
+                    .with_message("5.6.0 Message could not be parsed".to_string()),
+            ),
+            SessionError::MessageTooLarge => {
+                Some(Reply::MailActionAborted().with_message(
+                    "5.3.4 Message size exceeds fixed maximum message size".to_string(),
+                ))
+            }
+            SessionError::Handler(e) => {
+                Some(Reply::ActionAborted().with_message(format!("Mail not delivered: {}", e)))
+            }
+            SessionError::Rejected(e) => {
+                Some(Reply::TransactionFailed().with_message(format!("Mail not delivered: {}", e)))
+            }
+            SessionError::InsufficientStorage => Some(
+                Reply::InsufficientStorage()
+                    .with_message("4.3.1 Insufficient system storage".to_string()),
+            ),
+            SessionError::RelayDenied => Some(
+                Reply::TransactionFailed().with_message("5.7.1 Relay access denied".to_string()),
+            ),
+            SessionError::Alias(crate::aliases::ResolveError::InvalidAddress(_)) => {
+                Some(Reply::ActionAborted().with_message("4.3.0 Invalid alias".to_string()))
+            }
+            SessionError::Alias(_) => {
+                Some(Reply::ActionNotTaken().with_message("5.4.6 Alias loop".to_string()))
+            }
+            SessionError::TooManyRecipients => Some(
+                Reply::InsufficientStorage().with_message("4.5.3 Too many recipients".to_string()),
+            ),
+            SessionError::AuthenticationCancelled => Some(
+                Reply::SyntaxErrorInParametersOrArguments()
+                    .with_message("5.7.0 Authentication cancelled".to_string()),
+            ),
+            SessionError::InvalidAuthResponse => Some(
+                Reply::SyntaxErrorInParametersOrArguments()
+                    .with_message("5.5.2 Cannot decode the response".to_string()),
+            ),
+            SessionError::AuthenticationFailed => Some(
+                Reply::AuthenticationFailed()
+                    .with_message("5.7.8 Authentication credentials invalid".to_string()),
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Io(e) => write!(f, "i/o error: {}", e),
+            SessionError::Tls(e) => write!(f, "TLS error: {}", e),
+            SessionError::ConnectionClosed => write!(f, "connection closed by the client"),
+            SessionError::LineTooLong => write!(f, "line too long"),
+            SessionError::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            SessionError::Syntax(e) => write!(f, "syntax error: {}", e),
+            SessionError::BadSequence(e) => write!(f, "bad sequence of commands: {}", e),
+            SessionError::InvalidMessage(e) => write!(f, "invalid message: {}", e),
+            SessionError::MessageTooLarge => write!(f, "message too large"),
+            SessionError::Handler(e) => write!(f, "event handler error: {}", e),
+            SessionError::Rejected(e) => write!(f, "rejected by the event handler: {}", e),
+            SessionError::InsufficientStorage => write!(f, "insufficient storage"),
+            SessionError::AuthenticationCancelled => write!(f, "authentication cancelled"),
+            SessionError::InvalidAuthResponse => write!(f, "invalid authentication response"),
+            SessionError::AuthenticationFailed => write!(f, "authentication failed"),
+            SessionError::RelayDenied => write!(f, "relay access denied"),
+            SessionError::Alias(e) => write!(f, "cannot resolve the aliases: {}", e),
+            SessionError::TooManyRecipients => write!(f, "too many recipients"),
+            SessionError::TooManyErrors => write!(f, "too many errors"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::Io(e) => Some(e),
+            SessionError::Tls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SessionError {
+    fn from(e: std::io::Error) -> SessionError {
+        SessionError::Io(e)
+    }
+}
+
+impl From<crate::tls::LibraryError> for SessionError {
+    fn from(e: crate::tls::LibraryError) -> SessionError {
+        SessionError::Tls(e)
+    }
+}
@@ -1,17 +1,98 @@
+use crate::{error::SessionError, message::MessageReader, session::Session};
 use async_trait::async_trait;
+use tokio::io::AsyncReadExt;
 
 #[async_trait]
 pub trait EventHandler: Send + Sync {
+    /// Called with each parsed message by the default [on_message](EventHandler::on_message).
+    /// An error refuses the message temporarily.
     async fn on_mail<'b>(
         &self,
-        email: std::pin::Pin<&email_parser::email::Email<'b>>,
-    ) -> Result<(), String>;
+        _session: &Session,
+        _email: std::pin::Pin<&email_parser::email::Email<'b>>,
+    ) -> Result<(), String> {
+        Err("no delivery configured".to_string())
+    }
+
+    /// Called with the exact bytes of each message, including the trace fields added by the server, without the final `.`.
+    /// Override it to store messages byte for byte, or to parse them another way.
+    ///
+    /// The default parses the message and calls [on_mail](EventHandler::on_mail),
+    /// or [on_invalid_message](EventHandler::on_invalid_message) if it cannot be parsed.
+    async fn on_message(&self, session: &Session, message: &[u8]) -> Result<(), SessionError> {
+        match email_parser::email::Email::parse(message) {
+            Ok(email) => self
+                .on_mail(session, std::pin::Pin::new(&email))
+                .await
+                .map_err(SessionError::Handler),
+            Err(e) => {
+                self.on_invalid_message(session, message, format!("{:?}", e))
+                    .await
+            }
+        }
+    }
+
+    /// Called with each message while the client is still sending it, to deliver large messages without holding them in memory.
+    /// The message is complete when the reader reaches its end, and the reply is sent once this returns.
+    ///
+    /// The default reads the whole message and calls [on_message](EventHandler::on_message).
+    async fn on_message_stream(
+        &self,
+        session: &Session,
+        message: &mut MessageReader,
+    ) -> Result<(), SessionError> {
+        let mut buffer = Vec::new();
+        message.read_to_end(&mut buffer).await?;
+        self.on_message(session, &buffer).await
+    }
+
+    /// Called by the default [on_message](EventHandler::on_message) with the messages that cannot be parsed.
+    /// The default refuses them permanently, return `Ok(())` after storing the message to accept it.
+    async fn on_invalid_message(
+        &self,
+        _session: &Session,
+        _message: &[u8],
+        error: String,
+    ) -> Result<(), SessionError> {
+        Err(SessionError::InvalidMessage(error))
+    }
+
+    /// The number of mailboxes a transaction may have, or `None` for no limit. The RCPT commands adding more
+    /// get `452 4.5.3 Too many recipients`, and the client sends the message to them in another transaction.
+    /// The first recipient of a transaction is always accepted, with all the mailboxes of its aliases.
+    fn max_recipients(&self) -> Option<usize> {
+        None
+    }
 
     async fn expand_mailing_list(&self, _name: String) -> Option<Vec<String>> {
         None
     }
 
+    /// Whether a user exists, for VRFY. Called with the argument of VRFY, or with the address when it is
+    /// an address of a virtual domain of the [alias table](crate::SmtpServer::aliases) without alias.
     async fn verify_user(&self, _name: String) -> bool {
         false
     }
+
+    /// Map the [certificate](crate::session::ClientCertificate) of the client to the identity it authenticates as with AUTH EXTERNAL,
+    /// or return `None` to refuse it. The client may ask for an `authorization_identity`, which should then be the one returned.
+    /// Check the `username` and `password` of a client using AUTH PLAIN or LOGIN, and return the identity
+    /// it authenticates as, or `None` to refuse it. With PLAIN, the client may ask for an `authorization_identity`.
+    async fn authenticate(
+        &self,
+        _session: &Session,
+        _authorization_identity: Option<String>,
+        _username: String,
+        _password: String,
+    ) -> Option<String> {
+        None
+    }
+
+    async fn authenticate_external(
+        &self,
+        _session: &Session,
+        _authorization_identity: Option<String>,
+    ) -> Option<String> {
+        None
+    }
 }
@@ -2,13 +2,26 @@
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 
+pub mod aliases;
 pub mod commands;
 pub(crate) mod config;
+pub mod delivery;
+pub mod dns;
+pub mod error;
 pub mod events;
+pub mod listener;
 pub mod mda;
+pub mod message;
+pub mod policy;
+pub(crate) mod received;
 pub mod replies;
+pub mod session;
 pub mod smtp;
 pub(crate) mod stream;
+pub mod tls;
 
+pub use error::{AliasError, ServerError, SessionError, TlsError};
 pub use events::EventHandler;
+pub use listener::Listener;
 pub use mda::SmtpServer;
+pub use message::MessageReader;
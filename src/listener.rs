@@ -0,0 +1,129 @@
+use crate::events::EventHandler;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// How a listener uses TLS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsMode {
+    /// STARTTLS is not offered.
+    Disabled,
+    /// STARTTLS is offered if the server has a certificate.
+    StartTls,
+    /// STARTTLS must be issued before MAIL or RCPT.
+    StartTlsRequired,
+    /// The TLS handshake happens right after the connection is accepted, before the greeting (RFC 8314).
+    Implicit,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ListenAddress {
+    Tcp(Vec<String>),
+    Unix(PathBuf, u32),
+}
+
+/// A socket the server accepts clients on, with its own policies.
+///
+/// ```no_run
+/// # use smtp_server::{Listener, listener::TlsMode};
+/// let submission = Listener::tcp("submission", "[::]:587")
+///     .tls_mode(TlsMode::StartTlsRequired)
+///     .auth_required(true)
+///     .max_message_size(Some(25_000_000));
+/// ```
+#[derive(Clone)]
+pub struct Listener {
+    pub(crate) name: String,
+    pub(crate) address: ListenAddress,
+    /// A TCP address given to [bind](Listener::bind) on a unix socket listener, reported by [run](crate::SmtpServer::run).
+    pub(crate) invalid_address: Option<String>,
+    pub(crate) tls_mode: TlsMode,
+    pub(crate) auth_required: bool,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) event_handler: Option<Arc<dyn EventHandler>>,
+}
+
+impl Listener {
+    fn new(name: &str, address: ListenAddress) -> Listener {
+        Listener {
+            name: name.to_string(),
+            address,
+            invalid_address: None,
+            tls_mode: TlsMode::StartTls,
+            auth_required: false,
+            max_message_size: None,
+            event_handler: None,
+        }
+    }
+
+    /// Listen on a TCP address such as `0.0.0.0:25`. `[::]:25` listens on both IPv6 and IPv4.
+    /// The address can also be a hostname, in which case all the addresses it resolves to are bound.
+    pub fn tcp<A: ToString>(name: &str, address: A) -> Listener {
+        Listener::new(name, ListenAddress::Tcp(vec![address.to_string()]))
+    }
+
+    /// Listen on a Unix domain socket at `path`, created with the permissions `mode` (for example `0o660`).
+    /// A stale socket left at this path by a previous run is removed.
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>(name: &str, path: P, mode: u32) -> Listener {
+        Listener::new(name, ListenAddress::Unix(path.into(), mode))
+    }
+
+    /// Relay from other servers on port 25: STARTTLS is offered but optional, no authentication.
+    pub fn mx() -> Listener {
+        Listener::tcp("mx", "[::]:25")
+    }
+
+    /// Message submission on port 587 (RFC 6409): STARTTLS and authentication are mandatory.
+    /// The [EventHandler] must [authenticate](EventHandler::authenticate) the clients.
+    pub fn submission() -> Listener {
+        Listener::tcp("submission", "[::]:587")
+            .tls_mode(TlsMode::StartTlsRequired)
+            .auth_required(true)
+    }
+
+    /// Message submission over implicit TLS on port 465 (RFC 8314), with mandatory authentication.
+    pub fn submissions() -> Listener {
+        Listener::tcp("submissions", "[::]:465")
+            .tls_mode(TlsMode::Implicit)
+            .auth_required(true)
+    }
+
+    /// Also listen on another TCP address, for example on another interface of a multi-homed host.
+    /// On a [unix socket](Listener::unix) listener, [run](crate::SmtpServer::run) fails with
+    /// [ServerError::TcpAddressOnUnixSocket](crate::error::ServerError::TcpAddressOnUnixSocket).
+    pub fn bind<A: ToString>(mut self, address: A) -> Self {
+        match &mut self.address {
+            ListenAddress::Tcp(addresses) => addresses.push(address.to_string()),
+            ListenAddress::Unix(_, _) => {
+                self.invalid_address
+                    .get_or_insert_with(|| address.to_string());
+            }
+        }
+        self
+    }
+
+    pub fn tls_mode(mut self, tls_mode: TlsMode) -> Self {
+        self.tls_mode = tls_mode;
+        self
+    }
+
+    /// Refuse MAIL until the client has authenticated, over TLS, with PLAIN or LOGIN checked by
+    /// [EventHandler::authenticate], or with EXTERNAL and a client certificate, which needs the `rustls` feature
+    /// and an [EventHandler::authenticate_external] accepting the clients.
+    pub fn auth_required(mut self, auth_required: bool) -> Self {
+        self.auth_required = auth_required;
+        self
+    }
+
+    /// Maximum size of a message in bytes, advertised with the SIZE extension (RFC 1870).
+    pub fn max_message_size(mut self, max_message_size: Option<usize>) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Handle mail received on this listener with `event_handler` instead of the one of the server.
+    pub fn event_handler<T: EventHandler + 'static>(mut self, event_handler: T) -> Self {
+        self.event_handler = Some(Arc::new(event_handler));
+        self
+    }
+}
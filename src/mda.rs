@@ -1,60 +1,588 @@
+use crate::aliases::AliasTable;
 use crate::config::Config;
+use crate::dns::{Blocklists, Resolver};
+use crate::error::{ServerError, TlsError};
 use crate::events::EventHandler;
-use crate::smtp::handle_client;
-use native_tls::{Identity, TlsAcceptor};
-use std::fs::File;
-use std::io::prelude::*;
+use crate::listener::{ListenAddress, Listener};
+use crate::policy::{AccessList, RelayPolicy};
+use crate::session::{Peer, Session};
+use crate::smtp::{handle_client, refuse_client};
+use crate::tls::{Certificate, CertificateHandle, TlsSettings};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::net::TcpListener;
 
 pub struct SmtpServer {
     event_handler: Arc<dyn EventHandler>,
     config: Config,
-    port: u16,
+    tls_settings: TlsSettings,
+    port: Option<u16>,
+    addresses: Vec<String>,
+    listeners: Vec<Listener>,
 }
 
 impl SmtpServer {
     pub fn new<T: EventHandler + 'static>(event_handler: T, domain: &str) -> SmtpServer {
         SmtpServer {
             event_handler: Arc::new(event_handler),
-            port: 25,
+            port: Some(25),
+            addresses: Vec::new(),
+            listeners: Vec::new(),
             config: Config::new(domain.to_string()),
+            tls_settings: TlsSettings::default(),
         }
     }
 
+    /// The port of the default listener, 25 by default.
+    /// It is ignored once addresses are given to [bind](SmtpServer::bind), since they have their own ports.
     pub fn port(&mut self, port: u16) -> &mut Self {
-        self.port = port;
+        self.port = Some(port);
         self
     }
 
-    pub fn tls(&mut self, file: &str, password: &str) -> &mut Self {
-        let mut file = File::open(file).unwrap();
-        let mut identity = Vec::new();
-        file.read_to_end(&mut identity).unwrap();
-        let identity = Identity::from_pkcs12(&identity, password).unwrap();
-        let acceptor = TlsAcceptor::new(identity).unwrap();
-        self.config.tls_acceptor = Some(acceptor.into());
+    /// Bind the default listener to this address instead of all the interfaces.
+    /// Can be called several times, and accepts hostnames that resolve to several addresses.
+    /// The addresses include their port, so the one set with [port](SmtpServer::port) is ignored,
+    /// and they are not bound if [disable_tcp](SmtpServer::disable_tcp) is called.
+    ///
+    /// ```no_run
+    /// # use smtp_server::{EventHandler, SmtpServer};
+    /// # fn example(handler: impl EventHandler + 'static) {
+    /// SmtpServer::new(handler, "mail.example.com")
+    ///     .bind("203.0.113.25:25")
+    ///     .bind("[2001:db8::25]:25")
+    ///     .bind("mx.example.com:25");
+    /// # }
+    /// ```
+    pub fn bind<A: ToString>(&mut self, address: A) -> &mut Self {
+        self.addresses.push(address.to_string());
+        self
+    }
+
+    /// Stop listening on the default TCP port. Useful when the server is only reachable through other [listeners](SmtpServer::listener).
+    /// This disables the whole default listener, including the addresses given to [bind](SmtpServer::bind).
+    pub fn disable_tcp(&mut self) -> &mut Self {
+        self.port = None;
+        self
+    }
+
+    /// Also listen on a Unix domain socket at `path`, created with the permissions `mode` (for example `0o660`).
+    /// A stale socket left at this path by a previous run is removed.
+    #[cfg(unix)]
+    pub fn unix_socket<P: Into<PathBuf>>(&mut self, path: P, mode: u32) -> &mut Self {
+        self.listeners.push(Listener::unix("unix", path, mode));
+        self
+    }
+
+    /// Accept clients on another socket, with its own profile.
+    /// All listeners share the runtime and the event handler of the server, unless the listener has its own.
+    pub fn listener(&mut self, listener: Listener) -> &mut Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Enable STARTTLS with a PKCS#12 archive (`.pfx` or `.p12`).
+    pub fn tls<P: AsRef<Path>>(&mut self, file: P, password: &str) -> Result<&mut Self, TlsError> {
+        self.certificate(Certificate::from_pkcs12_file(file, password)?)
+    }
+
+    /// Enable STARTTLS with a PEM certificate chain and its private key, like the `fullchain.pem` and `privkey.pem` files of certbot.
+    pub fn tls_pem<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        chain: P,
+        private_key: Q,
+    ) -> Result<&mut Self, TlsError> {
+        self.certificate(Certificate::from_pem_files(chain, private_key)?)
+    }
+
+    /// Enable STARTTLS with a certificate loaded by the caller, for example from memory.
+    /// Replaces the previous certificate, if any.
+    pub fn certificate(&mut self, certificate: Certificate) -> Result<&mut Self, TlsError> {
+        match &self.config.certificate {
+            Some(handle) => handle.reload(certificate)?,
+            None => {
+                self.config.certificate = Some(CertificateHandle::new(
+                    certificate,
+                    self.tls_settings.clone(),
+                )?)
+            }
+        }
+        Ok(self)
+    }
+
+    /// Serve another certificate to the clients asking for `hostname` with SNI, for example when hosting several domains.
+    /// The hostname can be a wildcard like `*.example.com`.
+    /// The other clients get the certificate set with [tls](SmtpServer::tls) or [certificate](SmtpServer::certificate),
+    /// or this one if there is none.
+    ///
+    /// ```no_run
+    /// # use smtp_server::{tls::Certificate, EventHandler, SmtpServer};
+    /// # fn example(handler: impl EventHandler + 'static) -> Result<(), Box<dyn std::error::Error>> {
+    /// SmtpServer::new(handler, "mail.example.com")
+    ///     .tls_pem("example.com/fullchain.pem", "example.com/privkey.pem")?
+    ///     .certificate_for("mail.example.org", Certificate::from_pem_files("example.org/fullchain.pem", "example.org/privkey.pem")?)?
+    ///     .sni_domain(true);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn certificate_for(
+        &mut self,
+        hostname: &str,
+        certificate: Certificate,
+    ) -> Result<&mut Self, TlsError> {
+        match &self.config.certificate {
+            Some(handle) => handle.reload_for(hostname, certificate)?,
+            None => {
+                let handle =
+                    CertificateHandle::new(certificate.clone(), self.tls_settings.clone())?;
+                handle.reload_for(hostname, certificate)?;
+                self.config.certificate = Some(handle);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Restrict the TLS versions and cipher suites offered to the clients.
+    /// Choosing the cipher suites, requiring TLS 1.3 or asking for client certificates needs the `rustls` feature.
+    ///
+    /// ```no_run
+    /// # use smtp_server::{tls::{TlsSettings, TlsVersion}, EventHandler, SmtpServer};
+    /// # fn example(handler: impl EventHandler + 'static) -> Result<(), Box<dyn std::error::Error>> {
+    /// SmtpServer::new(handler, "mail.example.com")
+    ///     .tls_settings(TlsSettings::default().min_version(TlsVersion::Tls12))?
+    ///     .tls_pem("fullchain.pem", "privkey.pem")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn tls_settings(&mut self, settings: TlsSettings) -> Result<&mut Self, TlsError> {
+        settings.validate()?;
+        if let Some(handle) = &self.config.certificate {
+            handle.set_settings(settings.clone())?;
+        }
+        self.tls_settings = settings;
+        Ok(self)
+    }
+
+    /// Greet the clients with the hostname they asked for with SNI, if it has its own [certificate](SmtpServer::certificate_for),
+    /// instead of the domain of the server.
+    /// The greeting is only affected on listeners with implicit TLS, since the client asks after it with STARTTLS.
+    pub fn sni_domain(&mut self, enabled: bool) -> &mut Self {
+        self.config.sni_domain = enabled;
+        self
+    }
+
+    /// Prepend `Return-Path: <reverse-path>` to the messages, for handlers making the final delivery (RFC 5321 section 4.4).
+    /// The `Return-Path:` fields sent by the clients are removed.
+    pub fn return_path(&mut self, enabled: bool) -> &mut Self {
+        self.config.return_path = enabled;
+        self
+    }
+
+    /// Make the DNS lookups about the clients with this resolver instead of the [system one](crate::dns::SystemResolver).
+    pub fn resolver<R: Resolver + 'static>(&mut self, resolver: R) -> &mut Self {
+        self.config.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Resolve the recipients of RCPT, VRFY and EXPN with an alias table.
+    /// The messages are given to the [EventHandler] with the mailboxes the recipients lead to.
+    pub fn aliases(&mut self, aliases: AliasTable) -> &mut Self {
+        self.config.aliases = Some(Arc::new(aliases));
+        self
+    }
+
+    /// Refuse the recipients of other domains than the local ones to the clients the policy does not let relay.
+    pub fn relay_policy(&mut self, policy: RelayPolicy) -> &mut Self {
+        self.config.relay_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Refuse the connections of the clients the list denies, with `554` before the greeting.
+    /// A clone of the list can [reload](AccessList::reload) it while the server is running.
+    pub fn access_list(&mut self, access_list: AccessList) -> &mut Self {
+        self.config.access_list = Some(access_list);
+        self
+    }
 
+    /// Look the clients up in DNS blocklists when they connect, with the [resolver](SmtpServer::resolver) of the server.
+    pub fn blocklists(&mut self, blocklists: Blocklists) -> &mut Self {
+        self.config.blocklists = Some(Arc::new(blocklists));
         self
     }
 
-    pub fn run(&mut self) {
-        let config = Arc::new(self.config.clone());
+    /// A handle to [reload](CertificateHandle::reload) the certificates while the server is running.
+    /// Returns `None` if TLS is not enabled.
+    pub fn certificate_handle(&self) -> Option<CertificateHandle> {
+        self.config.certificate.clone()
+    }
+
+    /// Listen for clients and serve them forever.
+    ///
+    /// All listeners are bound before any client is accepted, so that an address already in use,
+    /// a hostname that cannot be resolved, a listener with implicit TLS but no certificate
+    /// or a TCP address on a unix socket listener is reported as an error.
+    pub fn run(&mut self) -> Result<(), ServerError> {
+        let mut listeners = self.listeners.clone();
+        if let Some(port) = self.port {
+            let mut default = Listener::tcp("default", format!("[::]:{}", port));
+            if !self.addresses.is_empty() {
+                default.address = ListenAddress::Tcp(self.addresses.clone());
+            }
+            listeners.insert(0, default);
+        }
 
         futures::executor::block_on(async move {
-            // open socket
-            let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port))
-                .await
-                .unwrap();
-
-            loop {
-                let event_handler = Arc::clone(&self.event_handler);
-                let config = Arc::clone(&config);
-                let (socket, _) = listener.accept().await.unwrap();
+            let mut bound = Vec::new();
+            for listener in listeners {
+                if let Some(address) = &listener.invalid_address {
+                    return Err(ServerError::TcpAddressOnUnixSocket {
+                        listener: listener.name,
+                        address: address.clone(),
+                    });
+                }
+                let config = Arc::new(self.config.for_listener(&listener));
+                if config.implicit_tls && config.certificate.is_none() {
+                    return Err(ServerError::MissingCertificate {
+                        listener: listener.name,
+                    });
+                }
+                let event_handler = match &listener.event_handler {
+                    Some(event_handler) => Arc::clone(event_handler),
+                    None => Arc::clone(&self.event_handler),
+                };
+
+                match listener.address {
+                    ListenAddress::Tcp(addresses) => {
+                        for address in addresses {
+                            for socket_address in resolve(&address).await? {
+                                // open socket
+                                let tcp_listener =
+                                    bind_tcp(socket_address).await.map_err(|error| {
+                                        ServerError::Bind {
+                                            address: socket_address.to_string(),
+                                            error,
+                                        }
+                                    })?;
+                                info!("Listening on {} ({})", socket_address, listener.name);
+                                bound.push((
+                                    Bound::Tcp(tcp_listener),
+                                    Arc::clone(&config),
+                                    Arc::clone(&event_handler),
+                                ));
+                            }
+                        }
+                    }
+                    #[cfg(unix)]
+                    ListenAddress::Unix(path, mode) => {
+                        let unix_listener =
+                            bind_unix_socket(&path, mode).map_err(|error| ServerError::Bind {
+                                address: path.display().to_string(),
+                                error,
+                            })?;
+                        info!("Listening on {} ({})", path.display(), listener.name);
+                        bound.push((Bound::Unix(unix_listener), config, event_handler));
+                    }
+                    #[cfg(not(unix))]
+                    ListenAddress::Unix(_, _) => unreachable!(),
+                }
+            }
+
+            let tasks = bound.into_iter().map(|(listener, config, event_handler)| {
+                tokio::spawn(serve(listener, config, event_handler))
+            });
+            futures::future::join_all(tasks).await;
+
+            Ok(())
+        })
+    }
+}
+
+enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// Accept clients forever.
+async fn serve(listener: Bound, config: Arc<Config>, event_handler: Arc<dyn EventHandler>) {
+    loop {
+        let event_handler = Arc::clone(&event_handler);
+        let config = Arc::clone(&config);
+
+        match &listener {
+            Bound::Tcp(listener) => {
+                let (socket, addr) = match listener.accept().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("Failed to accept a client on {}: {}", config.listener, e);
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let addr = canonical_address(addr);
+                // denied clients are refused before the TLS handshake of implicit TLS
+                if !config
+                    .access_list
+                    .as_ref()
+                    .map_or(true, |access_list| access_list.allows(addr.ip()))
+                {
+                    tokio::spawn(refuse_client(socket, addr, config));
+                    continue;
+                }
+                let session = Session::new(Peer::Tcp(addr), &config.listener);
                 tokio::spawn(async move {
-                    handle_client(socket, config, event_handler).await;
+                    handle_client(socket, session, config, event_handler).await;
                 });
             }
-        })
+            #[cfg(unix)]
+            Bound::Unix(listener) => {
+                let (socket, _) = match listener.accept().await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("Failed to accept a client on {}: {}", config.listener, e);
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let credentials = match socket.peer_cred() {
+                    Ok(credentials) => credentials,
+                    Err(e) => {
+                        warn!("Failed to get the credentials of a unix socket peer: {}", e);
+                        continue;
+                    }
+                };
+                let peer = Peer::Unix(crate::session::UnixCredentials {
+                    uid: credentials.uid(),
+                    gid: credentials.gid(),
+                    pid: credentials.pid(),
+                });
+                let session = Session::new(peer, &config.listener);
+                tokio::spawn(async move {
+                    handle_client(socket, session, config, event_handler).await;
+                });
+            }
+        }
+    }
+}
+
+/// Resolve an address to all the socket addresses it designates.
+async fn resolve(address: &str) -> Result<Vec<SocketAddr>, ServerError> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Ok(vec![address]);
+    }
+
+    match tokio::net::lookup_host(address).await {
+        Ok(addresses) => {
+            let mut addresses: Vec<SocketAddr> = addresses.collect();
+            addresses.dedup();
+            if addresses.is_empty() {
+                return Err(ServerError::Resolve {
+                    address: address.to_string(),
+                    error: std::io::Error::new(std::io::ErrorKind::NotFound, "no address found"),
+                });
+            }
+            Ok(addresses)
+        }
+        Err(error) => Err(ServerError::Resolve {
+            address: address.to_string(),
+            error,
+        }),
+    }
+}
+
+/// Bind a TCP listener. The unspecified IPv6 address `[::]` accepts both IPv6 and IPv4 clients,
+/// and falls back to `0.0.0.0` on hosts without IPv6.
+async fn bind_tcp(address: SocketAddr) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+    use std::net::Ipv4Addr;
+
+    if !(address.is_ipv6() && address.ip().is_unspecified()) {
+        return TcpListener::bind(address).await;
+    }
+
+    let socket = match Socket::new(Domain::ipv6(), Type::stream(), Some(Protocol::tcp())) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("IPv6 is unavailable ({}), listening on IPv4 only", e);
+            return TcpListener::bind((Ipv4Addr::UNSPECIFIED, address.port())).await;
+        }
+    };
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(address))?;
+    socket.listen(1024)?;
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+
+    TcpListener::from_std(listener)
+}
+
+/// IPv4 clients of a dual-stack listener appear as IPv4-mapped IPv6 addresses.
+fn canonical_address(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => address,
+        },
+        address => address,
+    }
+}
+
+/// Bind a Unix socket at `path`, with the permissions `mode`.
+/// The socket is created in a private directory and moved to `path` once its permissions are set,
+/// so that no client can connect before. A socket left by a previous run is replaced,
+/// unless a server still accepts connections on it.
+#[cfg(unix)]
+fn bind_unix_socket(
+    path: &std::path::Path,
+    mode: u32,
+) -> std::io::Result<tokio::net::UnixListener> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(Error::new(
+                    ErrorKind::AddrInUse,
+                    "another server is listening on this socket",
+                ));
+            }
+        }
+        Ok(_) => {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "a file that is not a socket exists at this path",
+            ))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy(),
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the path has no file name",
+            ))
+        }
+    };
+    let directory = path.with_file_name(format!(".{}.{}", file_name, std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&directory)?;
+    let temporary = directory.join("socket");
+    let result = tokio::net::UnixListener::bind(&temporary).and_then(|listener| {
+        std::fs::set_permissions(&temporary, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&temporary, path)?;
+        Ok(listener)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    let _ = std::fs::remove_dir(&directory);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Handler;
+
+    #[async_trait::async_trait]
+    impl EventHandler for Handler {}
+
+    #[test]
+    fn test_implicit_tls_without_certificate() {
+        let mut server = SmtpServer::new(Handler, "example.com");
+        server.disable_tcp().listener(
+            Listener::tcp("smtps", "127.0.0.1:0").tls_mode(crate::listener::TlsMode::Implicit),
+        );
+        assert!(
+            matches!(server.run(), Err(ServerError::MissingCertificate { listener }) if listener == "smtps")
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_tcp_address_on_unix_socket() {
+        let mut server = SmtpServer::new(Handler, "example.com");
+        server
+            .disable_tcp()
+            .listener(Listener::unix("local", "/nonexistent/smtp.sock", 0o600).bind("127.0.0.1:0"));
+        assert!(matches!(
+            server.run(),
+            Err(ServerError::TcpAddressOnUnixSocket { listener, address })
+                if listener == "local" && address == "127.0.0.1:0"
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_access_list() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let access_list = AccessList::new(Vec::new(), vec!["127.0.0.0/8".parse().unwrap()]);
+        // without a certificate, the clients passing the access list would be disconnected
+        let mut config = Config::new("example.com".to_string());
+        config.implicit_tls = true;
+        config.access_list = Some(access_list.clone());
+        tokio::spawn(serve(
+            Bound::Tcp(listener),
+            Arc::new(config),
+            Arc::new(Handler),
+        ));
+
+        let mut reply = String::new();
+        let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+        client.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "554 example.com 5.7.1 Service unavailable; client [127.0.0.1] denied by the access list\r\n");
+
+        access_list.reload(vec!["127.0.0.1".parse().unwrap()], Vec::new());
+        let mut reply = String::new();
+        let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+        client.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_bind_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory =
+            std::env::temp_dir().join(format!("smtp-server-socket-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("smtp.sock");
+
+        let listener = bind_unix_socket(&path, 0o660).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o660
+        );
+        // the socket of a running server is kept
+        assert_eq!(
+            bind_unix_socket(&path, 0o660).unwrap_err().kind(),
+            std::io::ErrorKind::AddrInUse
+        );
+        drop(listener);
+        // a stale socket is replaced
+        let _listener = bind_unix_socket(&path, 0o600).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let file = directory.join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(bind_unix_socket(&file, 0o660).is_err());
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 2);
+        std::fs::remove_dir_all(directory).unwrap();
     }
 }
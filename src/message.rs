@@ -0,0 +1,112 @@
+use bytes::Bytes;
+use futures::{channel::mpsc, SinkExt, Stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Number of lines buffered between the client and the handler.
+const CAPACITY: usize = 64;
+
+/// A message being received, given to [on_message_stream](crate::EventHandler::on_message_stream).
+///
+/// It reads the bytes of the message as the client sends them, already dot-unstuffed and without the final `.`.
+/// It fails if the client disconnects or the message is refused while it is being read, so that
+/// a truncated message is never mistaken for a complete one.
+pub struct MessageReader {
+    receiver: mpsc::Receiver<std::io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl AsyncRead for MessageReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.chunk.is_empty() {
+            match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.chunk = chunk,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = std::cmp::min(buf.remaining(), self.chunk.len());
+        buf.put_slice(&self.chunk.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The sending half of a [MessageReader]. The reader gets the end of the message when it is dropped.
+pub(crate) struct MessageWriter {
+    sender: mpsc::Sender<std::io::Result<Bytes>>,
+}
+
+impl MessageWriter {
+    /// Give some bytes to the reader. They are dropped if the reader is gone.
+    pub(crate) async fn write(&mut self, data: &[u8]) {
+        let _ = self.sender.send(Ok(Bytes::copy_from_slice(data))).await;
+    }
+
+    /// Make the reader fail instead of reaching the end of the message. Later writes are dropped.
+    pub(crate) async fn fail(&mut self, error: &str) {
+        let _ = self
+            .sender
+            .send(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                error,
+            )))
+            .await;
+        self.sender.close_channel();
+    }
+}
+
+pub(crate) fn channel() -> (MessageWriter, MessageReader) {
+    let (sender, receiver) = mpsc::channel(CAPACITY);
+    (
+        MessageWriter { sender },
+        MessageReader {
+            receiver,
+            chunk: Bytes::new(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_message_reader() {
+        futures::executor::block_on(async {
+            let (mut writer, mut reader) = channel();
+            let reading = async move {
+                let mut message = Vec::new();
+                reader.read_to_end(&mut message).await.map(|_| message)
+            };
+            let writing = async move {
+                for _ in 0..CAPACITY * 2 {
+                    writer.write(b"line\r\n").await;
+                }
+            };
+            let (message, ()) = futures::join!(reading, writing);
+            assert_eq!(message.unwrap(), b"line\r\n".repeat(CAPACITY * 2));
+
+            let (mut writer, mut reader) = channel();
+            writer.write(b"truncated").await;
+            writer.fail("message too large").await;
+            writer.write(b"ignored").await;
+            let mut message = Vec::new();
+            assert!(reader.read_to_end(&mut message).await.is_err());
+
+            // the reader may stop early
+            let (mut writer, reader) = channel();
+            drop(reader);
+            for _ in 0..CAPACITY * 2 {
+                writer.write(b"line\r\n").await;
+            }
+        });
+    }
+}
@@ -0,0 +1,329 @@
+use crate::session::{Peer, Session};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// A block of IP addresses, like `192.0.2.0/24` or `2001:db8::/32`.
+/// A single address is a block of one address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+/// An error parsing a [Cidr].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrParseError(String);
+
+impl std::fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid network {:?}", self.0)
+    }
+}
+
+impl std::error::Error for CidrParseError {}
+
+impl Cidr {
+    /// The block of the addresses starting with the `prefix` first bits of `ip`.
+    /// Returns `None` if the prefix is longer than the address.
+    pub fn new(ip: IpAddr, prefix: u8) -> Option<Cidr> {
+        let network = match ip {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                IpAddr::V4((u32::from(ip) & mask(prefix, 32) as u32).into())
+            }
+            IpAddr::V6(ip) if prefix <= 128 => {
+                IpAddr::V6((u128::from(ip) & mask(prefix, 128)).into())
+            }
+            _ => return None,
+        };
+        Some(Cidr { network, prefix })
+    }
+
+    /// Whether the address is in this block. IPv4-mapped IPv6 addresses are compared as IPv4 addresses.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask(self.prefix, 32) as u32 == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask(self.prefix, 128) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The mask of the `prefix` first bits of an address of `bits` bits.
+fn mask(prefix: u8, bits: u32) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => (u128::MAX >> (128 - bits)) & !((1u128 << (bits - prefix as u32)) - 1),
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Cidr, CidrParseError> {
+        let error = || CidrParseError(s.to_string());
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip.parse().map_err(|_| error())?;
+        let prefix = match (prefix, ip) {
+            (Some(prefix), _) if prefix.bytes().all(|b| b.is_ascii_digit()) => {
+                prefix.parse().map_err(|_| error())?
+            }
+            (Some(_), _) => return Err(error()),
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+        Cidr::new(ip, prefix).ok_or_else(error)
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Which clients may send messages to other domains than the local ones.
+///
+/// Without a relay policy, the server accepts recipients of any domain. With one, a recipient of another domain
+/// is refused with `554 5.7.1 Relay access denied`, unless the client is in one of the relay networks,
+/// connected to a Unix socket, or authenticated while authenticated users may relay.
+/// The local domains are those of the policy, the domain of the server and the virtual domains of the
+/// [alias table](crate::SmtpServer::aliases).
+///
+/// ```no_run
+/// # use smtp_server::{policy::RelayPolicy, EventHandler, SmtpServer};
+/// # fn example(handler: impl EventHandler + 'static) -> Result<(), Box<dyn std::error::Error>> {
+/// SmtpServer::new(handler, "mail.example.com").relay_policy(
+///     RelayPolicy::new()
+///         .domain("example.com")
+///         .network("127.0.0.0/8".parse()?)
+///         .network("2001:db8::/32".parse()?)
+///         .authenticated(true),
+/// );
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RelayPolicy {
+    domains: Vec<String>,
+    networks: Vec<Cidr>,
+    authenticated: bool,
+}
+
+impl RelayPolicy {
+    /// A policy refusing to relay for any client.
+    pub fn new() -> RelayPolicy {
+        RelayPolicy::default()
+    }
+
+    /// Accept the recipients of this domain from all clients.
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domains.push(domain.to_ascii_lowercase());
+        self
+    }
+
+    /// Let the clients of this network relay.
+    pub fn network(mut self, network: Cidr) -> Self {
+        self.networks.push(network);
+        self
+    }
+
+    /// Let the authenticated clients relay.
+    pub fn authenticated(mut self, enabled: bool) -> Self {
+        self.authenticated = enabled;
+        self
+    }
+
+    /// Whether the domain is one of the local domains of the policy.
+    pub fn is_local(&self, domain: &str) -> bool {
+        self.domains
+            .iter()
+            .any(|local| local.eq_ignore_ascii_case(domain))
+    }
+
+    /// Whether the client may send messages to other domains.
+    pub fn may_relay(&self, session: &Session) -> bool {
+        if self.authenticated && session.authenticated.is_some() {
+            return true;
+        }
+        match &session.peer {
+            Peer::Tcp(address) => self
+                .networks
+                .iter()
+                .any(|network| network.contains(address.ip())),
+            // the permissions of the socket restrict who can connect
+            Peer::Unix(_) => true,
+        }
+    }
+}
+
+/// The networks allowed or denied to connect, checked before the greeting.
+/// A denied client gets `554` and is disconnected.
+///
+/// An address matching several networks follows the most specific one, and the denial when it is both allowed
+/// and denied by networks of the same size. An address matching no network is allowed, unless only allowed
+/// networks are listed. Clients of Unix sockets are always allowed.
+///
+/// The list is a handle: its clones share the same networks, so that a clone kept by the caller can
+/// [reload](AccessList::reload) them while the server is running.
+///
+/// ```no_run
+/// # use smtp_server::{policy::AccessList, EventHandler, SmtpServer};
+/// # fn example(handler: impl EventHandler + 'static) -> Result<(), Box<dyn std::error::Error>> {
+/// let access_list = AccessList::new(vec!["192.0.2.0/24".parse()?], vec!["0.0.0.0/0".parse()?, "::/0".parse()?]);
+/// SmtpServer::new(handler, "mail.example.com").access_list(access_list.clone());
+/// // later
+/// access_list.reload(Vec::new(), vec!["198.51.100.0/24".parse()?]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    networks: Arc<RwLock<Networks>>,
+}
+
+#[derive(Debug, Default)]
+struct Networks {
+    allowed: Vec<Cidr>,
+    denied: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allowed: Vec<Cidr>, denied: Vec<Cidr>) -> AccessList {
+        AccessList {
+            networks: Arc::new(RwLock::new(Networks { allowed, denied })),
+        }
+    }
+
+    /// Replace the networks for the next clients.
+    pub fn reload(&self, allowed: Vec<Cidr>, denied: Vec<Cidr>) {
+        *self
+            .networks
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Networks { allowed, denied };
+    }
+
+    /// Whether a client with this address may connect.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let networks = self
+            .networks
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let longest_match = |list: &[Cidr]| {
+            list.iter()
+                .filter(|network| network.contains(ip))
+                .map(|network| network.prefix)
+                .max()
+        };
+        match (
+            longest_match(&networks.allowed),
+            longest_match(&networks.denied),
+        ) {
+            (Some(allowed), Some(denied)) => allowed > denied,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => networks.allowed.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let network: Cidr = "192.0.2.77/24".parse().unwrap();
+        assert_eq!(network.to_string(), "192.0.2.0/24");
+        assert!(network.contains("192.0.2.1".parse().unwrap()));
+        assert!(network.contains("::ffff:192.0.2.255".parse().unwrap()));
+        assert!(!network.contains("192.0.3.1".parse().unwrap()));
+        assert!(!network.contains("2001:db8::1".parse().unwrap()));
+
+        let network: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!network.contains("2001:db9::1".parse().unwrap()));
+
+        let host: Cidr = "203.0.113.25".parse().unwrap();
+        assert!(host.contains("203.0.113.25".parse().unwrap()));
+        assert!(!host.contains("203.0.113.26".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("198.51.100.1".parse().unwrap()));
+        assert!("::/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("::1".parse().unwrap()));
+
+        for invalid in [
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/",
+            "192.0.2.0/+8",
+            "example.com/24",
+            "",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_relay_policy() {
+        let policy = RelayPolicy::new()
+            .domain("Example.com")
+            .network("192.0.2.0/24".parse().unwrap())
+            .authenticated(true);
+        assert!(policy.is_local("example.COM"));
+        assert!(!policy.is_local("example.net"));
+
+        let mut session = Session::new(Peer::Tcp("198.51.100.1:4321".parse().unwrap()), "test");
+        assert!(!policy.may_relay(&session));
+        session.authenticated = Some("alice".to_string());
+        assert!(policy.may_relay(&session));
+        assert!(!RelayPolicy::new().may_relay(&session));
+
+        session.authenticated = None;
+        session.peer = Peer::Tcp("192.0.2.1:4321".parse().unwrap());
+        assert!(policy.may_relay(&session));
+    }
+
+    #[test]
+    fn test_access_list() {
+        let ip = |ip: &str| ip.parse().unwrap();
+        let access_list = AccessList::new(
+            vec!["192.0.2.0/24".parse().unwrap()],
+            vec![
+                "0.0.0.0/0".parse().unwrap(),
+                "192.0.2.128/25".parse().unwrap(),
+            ],
+        );
+        assert!(access_list.allows(ip("192.0.2.1")));
+        assert!(!access_list.allows(ip("192.0.2.200")));
+        assert!(!access_list.allows(ip("198.51.100.1")));
+        // only the allowed networks may connect when some are listed
+        assert!(!access_list.allows(ip("2001:db8::1")));
+
+        let shared = access_list.clone();
+        shared.reload(vec!["2001:db8::/32".parse().unwrap()], Vec::new());
+        assert!(access_list.allows(ip("2001:db8::1")));
+        assert!(!access_list.allows(ip("198.51.100.1")));
+        shared.reload(
+            vec!["192.0.2.1".parse().unwrap()],
+            vec!["192.0.2.1".parse().unwrap()],
+        );
+        assert!(!access_list.allows(ip("192.0.2.1")));
+        assert!(AccessList::default().allows(ip("192.0.2.1")));
+    }
+}
@@ -0,0 +1,287 @@
+use crate::session::{Envelope, Greeting, Peer, Session};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A new identifier for a mail transaction, unique for the lifetime of the process.
+pub(crate) fn queue_id() -> String {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed) & 0xFFFFF;
+    format!("{:X}{:05X}", elapsed.as_secs(), counter)
+}
+
+/// The `Received:` trace field added to each accepted message (RFC 5321 section 4.4), with its CRLF.
+pub(crate) fn received_header(
+    session: &Session,
+    domain: &str,
+    server_agent: &str,
+    date: SystemTime,
+) -> String {
+    let helo = session
+        .greeting
+        .as_ref()
+        .map(Greeting::name)
+        .unwrap_or("unknown");
+    let client = match &session.peer {
+        Peer::Tcp(address) => format!(
+            "{} [{}]",
+            session.reverse_dns.as_deref().unwrap_or("unknown"),
+            match address.ip() {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("IPv6:{}", ip),
+            }
+        ),
+        peer => peer.to_string(),
+    };
+
+    // protocol types of RFC 3848
+    let protocol = match session.greeting {
+        Some(Greeting::Ehlo(_)) => match (session.tls.is_some(), session.authenticated.is_some()) {
+            (false, false) => "ESMTP",
+            (false, true) => "ESMTPA",
+            (true, false) => "ESMTPS",
+            (true, true) => "ESMTPSA",
+        },
+        _ => "SMTP",
+    };
+    let mut with = protocol.to_string();
+    if let Some(tls) = &session.tls {
+        match (&tls.protocol, &tls.cipher) {
+            (Some(version), Some(cipher)) => {
+                with.push_str(&format!(" (version={} cipher={})", version, cipher))
+            }
+            (Some(version), None) => with.push_str(&format!(" (version={})", version)),
+            _ => (),
+        }
+    }
+
+    let mut header = format!(
+        "Received: from {} ({})\r\n\tby {} ({}) with {}",
+        helo, client, domain, server_agent, with
+    );
+    if let Some(envelope) = &session.envelope {
+        header.push_str(&format!(" id {}", envelope.id));
+        // recipients are only disclosed to themselves
+        if let [recipient] = envelope.recipients.as_slice() {
+            header.push_str(&format!("\r\n\tfor <{}>", recipient));
+        }
+    }
+    header.push_str(&format!(";\r\n\t{}\r\n", format_date(date)));
+    header
+}
+
+/// The `Return-Path:` field recording the reverse path at final delivery (RFC 5321 section 4.4), with its CRLF.
+pub(crate) fn return_path_header(envelope: &Envelope) -> String {
+    match &envelope.reverse_path {
+        Some(reverse_path) => format!("Return-Path: <{}>\r\n", reverse_path),
+        None => "Return-Path: <>\r\n".to_string(),
+    }
+}
+
+/// Removes the header fields named `name`, with their folded lines, from a message read line by line.
+/// Used to drop the `Return-Path:` fields a client may have sent.
+pub(crate) struct FieldFilter<'a> {
+    name: &'a str,
+    removing: bool,
+    in_header: bool,
+}
+
+impl<'a> FieldFilter<'a> {
+    pub(crate) fn new(name: &'a str) -> FieldFilter<'a> {
+        FieldFilter {
+            name,
+            removing: false,
+            in_header: true,
+        }
+    }
+
+    /// Whether the next line of the message, with its CRLF, is kept.
+    pub(crate) fn keep(&mut self, line: &[u8]) -> bool {
+        // the header section ends at the first empty line
+        if !self.in_header || line == b"\r\n" {
+            self.in_header = false;
+            return true;
+        }
+
+        let continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !continuation {
+            let field_name = line.split(|b| *b == b':').next().unwrap_or_default();
+            self.removing =
+                line.contains(&b':') && field_name.eq_ignore_ascii_case(self.name.as_bytes());
+        }
+        !self.removing
+    }
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A date in the format of RFC 5322 section 3.3, in UTC.
+pub(crate) fn format_date(date: SystemTime) -> String {
+    let (days, year, month, day, seconds) = civil_date(date);
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// A date in the format of the C function `asctime`, in UTC, as used by the `From ` lines of mbox files.
+pub(crate) fn format_asctime(date: SystemTime) -> String {
+    let (days, year, month, day, seconds) = civil_date(date);
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {}",
+        DAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        year
+    )
+}
+
+/// The number of days since 1970-01-01, the year, month and day, and the seconds since midnight of a date in UTC.
+fn civil_date(date: SystemTime) -> (u64, i64, i64, i64, u64) {
+    let seconds = date
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = seconds / 86400;
+    let seconds = seconds % 86400;
+
+    // civil date from the number of days since 1970-01-01, by Howard Hinnant
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (days, year, month, day, seconds)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::{Address, Envelope, TlsInfo};
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(UNIX_EPOCH), "Thu, 1 Jan 1970 00:00:00 +0000");
+        assert_eq!(
+            format_date(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "Tue, 29 Feb 2000 00:00:00 +0000"
+        );
+        assert_eq!(
+            format_date(UNIX_EPOCH + Duration::from_secs(1_792_404_000)),
+            "Mon, 19 Oct 2026 10:00:00 +0000"
+        );
+        assert_eq!(
+            format_asctime(UNIX_EPOCH + Duration::from_secs(1_792_404_000)),
+            "Mon Oct 19 10:00:00 2026"
+        );
+        assert_eq!(format_asctime(UNIX_EPOCH), "Thu Jan  1 00:00:00 1970");
+    }
+
+    #[test]
+    fn test_received_header() {
+        let date = UNIX_EPOCH + Duration::from_secs(1_792_404_000);
+        let recipient = Address {
+            local_part: "bob".to_string(),
+            domain: "example.com".to_string(),
+        };
+        let mut session = Session::new(Peer::Tcp("192.0.2.1:4321".parse().unwrap()), "test");
+        session.greeting = Some(Greeting::Helo("client.example.net".to_string()));
+        session.envelope = Some(Envelope {
+            id: "ABC".to_string(),
+            reverse_path: None,
+            recipients: vec![recipient.clone()],
+        });
+        assert_eq!(
+            received_header(&session, "example.com", "Rust SMTP server", date),
+            "Received: from client.example.net (unknown [192.0.2.1])\r\n\
+             \tby example.com (Rust SMTP server) with SMTP id ABC\r\n\
+             \tfor <bob@example.com>;\r\n\
+             \tMon, 19 Oct 2026 10:00:00 +0000\r\n"
+        );
+
+        session.peer = Peer::Tcp("[2001:db8::1]:4321".parse().unwrap());
+        session.reverse_dns = Some("mail.example.net".to_string());
+        session.greeting = Some(Greeting::Ehlo("mail.example.net".to_string()));
+        session.authenticated = Some("relay".to_string());
+        session.tls = Some(TlsInfo {
+            protocol: Some("TLSv1.3".to_string()),
+            cipher: Some("TLS13_AES_256_GCM_SHA384".to_string()),
+            client_certificate: None,
+        });
+        session.envelope.as_mut().unwrap().recipients.push(Address {
+            local_part: "carol".to_string(),
+            domain: "example.com".to_string(),
+        });
+        assert_eq!(
+            received_header(&session, "example.com", "Rust SMTP server", date),
+            "Received: from mail.example.net (mail.example.net [IPv6:2001:db8::1])\r\n\
+             \tby example.com (Rust SMTP server) with ESMTPSA (version=TLSv1.3 cipher=TLS13_AES_256_GCM_SHA384) id ABC;\r\n\
+             \tMon, 19 Oct 2026 10:00:00 +0000\r\n"
+        );
+    }
+
+    #[test]
+    fn test_return_path() {
+        let mut envelope = Envelope::default();
+        assert_eq!(return_path_header(&envelope), "Return-Path: <>\r\n");
+        envelope.reverse_path = Some(Address {
+            local_part: "alice smith".to_string(),
+            domain: "example.com".to_string(),
+        });
+        assert_eq!(
+            return_path_header(&envelope),
+            "Return-Path: <\"alice smith\"@example.com>\r\n"
+        );
+
+        let message = [
+            "Return-Path: <forged@example.net>\r\n",
+            "From: alice@example.com\r\n",
+            "return-path:\r\n",
+            " <folded@example.net>\r\n",
+            "\r\n",
+            "Return-Path: kept in the body\r\n",
+        ];
+        let mut filter = FieldFilter::new("Return-Path");
+        let kept: String = message
+            .iter()
+            .filter(|line| filter.keep(line.as_bytes()))
+            .copied()
+            .collect();
+        assert_eq!(
+            kept,
+            "From: alice@example.com\r\n\r\nReturn-Path: kept in the body\r\n"
+        );
+    }
+
+    #[test]
+    fn test_queue_id() {
+        assert_ne!(queue_id(), queue_id());
+    }
+}
@@ -183,6 +183,13 @@ where
         }
     }
 
+    pub fn AuthenticationRequired() -> Reply<T> {
+        Reply {
+            reply_type: ReplyType::AuthenticationRequired,
+            message: None,
+        }
+    }
+
     pub fn TlsUnavailable() -> Reply<T> {
         Reply {
             reply_type: ReplyType::TlsUnavailable,
@@ -190,6 +197,27 @@ where
         }
     }
 
+    pub fn AuthenticationSucceeded() -> Reply<T> {
+        Reply {
+            reply_type: ReplyType::AuthenticationSucceeded,
+            message: None,
+        }
+    }
+
+    pub fn ServerChallenge() -> Reply<T> {
+        Reply {
+            reply_type: ReplyType::ServerChallenge,
+            message: None,
+        }
+    }
+
+    pub fn AuthenticationFailed() -> Reply<T> {
+        Reply {
+            reply_type: ReplyType::AuthenticationFailed,
+            message: None,
+        }
+    }
+
     pub fn with_message(self, message: T) -> Reply<T> {
         Reply {
             message: Some(message),
@@ -220,10 +248,13 @@ pub enum ReplyType {
     UserNotLocalHandled,
     UserNotLocal,
     CannotVerifyUser,
+    AuthenticationSucceeded,
     StartMailInput,
+    ServerChallenge,
     ServiceUnavailable,
     MailActionNotTaken,
     TlsRequired,
+    AuthenticationRequired,
     ActionNotTaken,
     MailActionAborted,
     ActionAborted,
@@ -237,12 +268,13 @@ pub enum ReplyType {
     CommandParameterNotImplemented,
     MailboxNotCorrect,
     TransactionFailed,
+    AuthenticationFailed,
     Unknown,
 }
 
-impl Into<usize> for ReplyType {
-    fn into(self) -> usize {
-        match self {
+impl From<ReplyType> for usize {
+    fn from(reply_type: ReplyType) -> usize {
+        match reply_type {
             ReplyType::SystemStatus => 211,
             ReplyType::HelpMessage => 214,
             ReplyType::ServiceReady => 220,
@@ -250,7 +282,9 @@ impl Into<usize> for ReplyType {
             ReplyType::Ok => 250,
             ReplyType::UserNotLocalHandled => 251,
             ReplyType::CannotVerifyUser => 252,
+            ReplyType::AuthenticationSucceeded => 235,
             ReplyType::StartMailInput => 354,
+            ReplyType::ServerChallenge => 334,
             ReplyType::ServiceUnavailable => 421,
             ReplyType::MailActionNotTaken => 450,
             ReplyType::ActionAborted => 451,
@@ -263,6 +297,8 @@ impl Into<usize> for ReplyType {
             ReplyType::BadSequenceOfCommands => 503,
             ReplyType::CommandParameterNotImplemented => 504,
             ReplyType::TlsRequired => 530,
+            ReplyType::AuthenticationRequired => 530,
+            ReplyType::AuthenticationFailed => 535,
             ReplyType::ActionNotTaken => 550,
             ReplyType::UserNotLocal => 551,
             ReplyType::MailActionAborted => 552,
@@ -283,6 +319,8 @@ impl From<usize> for ReplyType {
             250 => ReplyType::Ok,
             251 => ReplyType::UserNotLocalHandled,
             252 => ReplyType::CannotVerifyUser,
+            235 => ReplyType::AuthenticationSucceeded,
+            334 => ReplyType::ServerChallenge,
             354 => ReplyType::StartMailInput,
             421 => ReplyType::ServiceUnavailable,
             450 => ReplyType::MailActionNotTaken,
@@ -296,6 +334,7 @@ impl From<usize> for ReplyType {
             503 => ReplyType::BadSequenceOfCommands,
             504 => ReplyType::CommandParameterNotImplemented,
             530 => ReplyType::TlsRequired,
+            535 => ReplyType::AuthenticationFailed,
             550 => ReplyType::ActionNotTaken,
             551 => ReplyType::UserNotLocal,
             552 => ReplyType::MailActionAborted,
@@ -309,14 +348,22 @@ impl From<usize> for ReplyType {
     }
 }
 
-impl<T> ToString for Reply<T>
+impl<T> std::fmt::Display for Reply<T>
 where
     T: std::fmt::Display,
 {
-    fn to_string(&self) -> String {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.message {
-            Some(ref m) => process_message(self.reply_type.clone().into(), &m.to_string()),
-            None => process_message(self.reply_type.clone().into(), "undefined"),
+            Some(ref m) => write!(
+                f,
+                "{}",
+                process_message(self.reply_type.clone().into(), &m.to_string())
+            ),
+            None => write!(
+                f,
+                "{}",
+                process_message(self.reply_type.clone().into(), "undefined")
+            ),
         }
     }
 }
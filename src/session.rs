@@ -0,0 +1,182 @@
+use crate::commands::{LocalPart, LocalPartRules, Path, Recipient, ServerIdentity};
+use std::net::SocketAddr;
+
+/// Credentials of the process on the other end of a Unix domain socket (SO_PEERCRED).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnixCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+/// The client connected to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix(UnixCredentials),
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(c) => write!(f, "unix:uid={},gid={}", c.uid, c.gid),
+        }
+    }
+}
+
+/// An address of the envelope, owned so that it outlives the command it was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    /// The local part, without quotes.
+    pub local_part: String,
+    /// The domain, or an address literal like `[192.0.2.1]`.
+    pub domain: String,
+}
+
+impl Address {
+    /// Parse an address written like in a path, without the angle brackets: `alice@example.com` or `"alice smith"@example.com`.
+    pub fn parse(address: &str) -> Option<Address> {
+        let (local_part, identity) = crate::commands::parse_mailbox(address)?;
+        Some(Address::from_mailbox(&local_part, &identity))
+    }
+
+    pub(crate) fn from_mailbox(local_part: &LocalPart, identity: &ServerIdentity) -> Address {
+        Address {
+            local_part: local_part.as_str().to_string(),
+            domain: identity.to_string(),
+        }
+    }
+
+    /// The address of the mailbox of this address, without the detail of the local part and normalized:
+    /// `Alice+lists@Example.com` is `alice@example.com` with the default rules.
+    pub fn mailbox(&self, rules: &LocalPartRules) -> Address {
+        Address {
+            local_part: LocalPart::from_unquoted(&self.local_part)
+                .split_detail(rules)
+                .0,
+            domain: self.domain.to_lowercase(),
+        }
+    }
+
+    /// The normalized detail of the local part, `lists` in `alice+lists@example.com`.
+    pub fn detail(&self, rules: &LocalPartRules) -> Option<String> {
+        LocalPart::from_unquoted(&self.local_part)
+            .split_detail(rules)
+            .1
+    }
+
+    /// The address of a recipient. The local postmaster is given the domain of the server.
+    pub(crate) fn from_recipient(recipient: &Recipient, local_domain: &str) -> Address {
+        match recipient {
+            Recipient::LocalPostmaster => Address {
+                local_part: String::from("postmaster"),
+                domain: local_domain.to_string(),
+            },
+            Recipient::Postmaster(domain) => Address {
+                local_part: String::from("postmaster"),
+                domain: domain.to_string(),
+            },
+            Recipient::Path(Path(_source_route, (local_part, identity))) => {
+                Address::from_mailbox(local_part, identity)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}@{}",
+            LocalPart::from_unquoted(&self.local_part).to_quoted(),
+            self.domain
+        )
+    }
+}
+
+/// How the client introduced itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Greeting {
+    /// EHLO, the client supports the SMTP extensions.
+    Ehlo(String),
+    /// HELO, from older clients.
+    Helo(String),
+}
+
+impl Greeting {
+    /// The domain or address literal the client gave.
+    pub fn name(&self) -> &str {
+        match self {
+            Greeting::Ehlo(name) | Greeting::Helo(name) => name,
+        }
+    }
+}
+
+/// A mail transaction, started by MAIL FROM.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// The queue ID of the transaction, unique for the lifetime of the process and written in the `Received:` header.
+    pub id: String,
+    /// The reverse path, or `None` for the null reverse path `<>` used by bounces.
+    pub reverse_path: Option<Address>,
+    pub recipients: Vec<Address>,
+}
+
+/// The TLS parameters negotiated with the client. They are only known with the `rustls` feature.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The protocol version, like `TLSv1.3`.
+    pub protocol: Option<String>,
+    /// The cipher suite, like `TLS13_AES_256_GCM_SHA384`.
+    pub cipher: Option<String>,
+    /// The certificate of the client, if it was [asked for](crate::tls::TlsSettings::client_ca) and verified.
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// A certificate presented by the client and issued by one of the trusted CAs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The certificate in DER.
+    pub der: Vec<u8>,
+    /// The SHA-256 digest of the DER, in uppercase hexadecimal pairs separated by colons, like `openssl x509 -fingerprint -sha256`.
+    pub fingerprint: String,
+}
+
+/// What the server knows about the client. It is given to the [EventHandler](crate::EventHandler) hooks.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub peer: Peer,
+    /// The hostname of the client, if its address points to it and it points back to the address.
+    pub reverse_dns: Option<String>,
+    /// The name of the [Listener](crate::Listener) the client connected to.
+    pub listener: String,
+    /// How the client introduced itself, forgotten after STARTTLS (RFC 3207 section 4.2).
+    pub greeting: Option<Greeting>,
+    /// The identity the client authenticated as.
+    pub authenticated: Option<String>,
+    /// The current mail transaction.
+    pub envelope: Option<Envelope>,
+    /// The TLS parameters, if the connection is encrypted.
+    pub tls: Option<TlsInfo>,
+    /// The zones of the [DNS blocklists](crate::dns::Blocklists) listing the client.
+    pub blocklists: Vec<String>,
+    /// The sum of the weights of the blocklists listing the client.
+    pub blocklist_score: u32,
+}
+
+impl Session {
+    pub(crate) fn new(peer: Peer, listener: &str) -> Session {
+        Session {
+            peer,
+            reverse_dns: None,
+            listener: listener.to_string(),
+            greeting: None,
+            authenticated: None,
+            envelope: None,
+            tls: None,
+            blocklists: Vec::new(),
+            blocklist_score: 0,
+        }
+    }
+}
@@ -1,205 +1,1980 @@
-use crate::{commands::*, config::Config, replies::Reply, stream::TcpStream};
-use bytes::BytesMut;
+#![forbid(unsafe_code)]
+
+use crate::{
+    aliases::AliasTable,
+    commands::*,
+    config::Config,
+    error::SessionError,
+    events::EventHandler,
+    message::{self, MessageWriter},
+    received::{queue_id, received_header, return_path_header, FieldFilter},
+    replies::Reply,
+    session::{Address, Envelope, Greeting, Peer, Session},
+    stream::{Connection, Stream},
+    tls::CertificateHandle,
+};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use tokio::net::TcpStream as TokioTcpStream;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Number of errors after which the client is disconnected.
+const MAX_ERRORS: usize = 10;
+/// Maximum length of a command line, including the CRLF.
+/// RFC 5321 allows 512 octets, plus some more for each extension adding parameters.
+const MAX_COMMAND_LINE_LENGTH: usize = 2048;
+/// Maximum length of a line of a message, including the CRLF.
+/// RFC 5321 allows 1000 octets, but some clients do not wrap lines.
+const MAX_TEXT_LINE_LENGTH: usize = 64 * 1024;
 
-pub(crate) async fn handle_client(
-    socket: TokioTcpStream,
+pub(crate) async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    session: Session,
     config: std::sync::Arc<Config>,
     event_handler: std::sync::Arc<dyn crate::events::EventHandler>,
 ) {
-    debug!("New client: {:?}", socket);
-    let mut socket = TcpStream::Unencrypted(socket);
-
-    socket
-        .send_reply(Reply::ServiceReady().with_message(format!(
-            "{} {}: Service ready",
-            config.domain, config.server_agent
-        )))
-        .await
-        .unwrap();
+    debug!("New client: {}", session.peer);
+    let peer = session.peer.clone();
+    match serve_client(Stream::Unencrypted(socket), session, config, event_handler).await {
+        Ok(()) => debug!("Client {} disconnected", peer),
+        Err(e) => warn!("Session with {} ended: {}", peer, e),
+    }
+}
 
-    let mut reverse_path: Option<(LocalPart, ServerIdentity)> = None;
-    let mut forward_path: Vec<Recipient> = Vec::new();
+/// The reply refusing a client in place of the greeting.
+fn refusal(domain: &str, address: SocketAddr, reason: &str) -> Reply {
+    Reply::TransactionFailed().with_message(format!(
+        "{} 5.7.1 Service unavailable; client [{}] {}",
+        domain,
+        address.ip(),
+        reason
+    ))
+}
 
-    loop {
-        let mut b = BytesMut::new();
-
-        // The `read` method is defined by this trait.
-        let n = socket.read_buf(&mut b).await.unwrap();
-        let s = std::str::from_utf8(unsafe {
-            // BIG WARNING:
-            // This is disabling compiler lifetime checks on the received data.
-            // However, your are still disallowed to move references to this data outside of this scope.
-            // Please call to_owned() when you need to save data.
-            std::mem::transmute::<_, &'static [u8]>(&b[..n])
-        }).unwrap();
-
-        if s.is_empty() {
-            warn!("Empty packet received.");
-            socket.shutdown().await.unwrap();
-            break;
+/// Refuse a client denied by the access list, before the greeting and without a TLS handshake.
+pub(crate) async fn refuse_client<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    address: SocketAddr,
+    config: std::sync::Arc<Config>,
+) {
+    info!("Refusing the connection of {}", address);
+    let mut connection = Connection::new(Stream::Unencrypted(socket));
+    let reply = refusal(&config.domain, address, "denied by the access list");
+    if let Err(e) = async {
+        connection.send_reply(reply).await?;
+        connection.shutdown().await
+    }
+    .await
+    {
+        debug!("Cannot refuse {}: {}", address, e);
+    }
+}
+
+async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: Stream<S>,
+    mut session: Session,
+    config: std::sync::Arc<Config>,
+    event_handler: std::sync::Arc<dyn crate::events::EventHandler>,
+) -> Result<(), SessionError> {
+    let mut connection = Connection::new(socket);
+    // the certificates may be reloaded during the session, but this client keeps the ones it was offered
+    let certificates = config
+        .certificate
+        .as_ref()
+        .map(CertificateHandle::certificates);
+    let mut domain = config.domain.clone();
+
+    if config.implicit_tls {
+        let certificates = match &certificates {
+            Some(certificates) => certificates,
+            None => {
+                error!(
+                    "Implicit TLS is enabled on listener {} but no certificate is configured",
+                    config.listener
+                );
+                return Ok(());
+            }
+        };
+        let (encrypted, hostname) = connection.accept(certificates).await?;
+        connection = encrypted;
+        session.tls = connection.tls_info();
+        if let (true, Some(hostname)) = (config.sni_domain, hostname) {
+            domain = hostname;
         }
+    }
 
-        let command = match Command::from_str(s) {
-            Ok(command) => {
-                debug!("Received command: {:?}", command);
-                command
+    if let Peer::Tcp(address) = session.peer {
+        let blocklists = async {
+            match &config.blocklists {
+                Some(blocklists) => {
+                    blocklists
+                        .lookup(config.resolver.as_ref(), address.ip())
+                        .await
+                }
+                None => Vec::new(),
             }
-            Err(e) => {
-                error!("Failed to parse command: {:?} -> {:?}", s, e);
-                socket
-                    .send_reply(
-                        Reply::SyntaxError().with_message("Unrecognized command".to_string()),
-                    )
-                    .await
-                    .unwrap();
-                continue;
+        };
+        let (reverse_dns, listed) = futures::join!(
+            crate::dns::confirmed_hostname(config.resolver.as_ref(), address.ip()),
+            blocklists
+        );
+        session.reverse_dns = reverse_dns;
+        if let Some(blocklists) = &config.blocklists {
+            session.blocklist_score = blocklists.score(&listed);
+            if blocklists.rejects(session.blocklist_score) {
+                info!(
+                    "Refusing the connection of {}, listed by {}",
+                    address,
+                    listed.join(", ")
+                );
+                let reason = format!("blocked using {}", listed.join(", "));
+                connection
+                    .send_reply(refusal(&domain, address, &reason))
+                    .await?;
+                return Ok(());
             }
+            session.blocklists = listed;
+        }
+    }
+
+    connection
+        .send_reply(
+            Reply::ServiceReady()
+                .with_message(format!("{} {}: Service ready", domain, config.server_agent)),
+        )
+        .await?;
+
+    let mut errors = 0;
+
+    loop {
+        // The line is owned by this iteration and the command borrows from it.
+        // Everything that must outlive the command is copied into the session.
+        let line = match connection.read_line(MAX_COMMAND_LINE_LENGTH).await {
+            Ok(Some(line)) => Ok(line),
+            Ok(None) => return Ok(()),
+            Err(e) => Err(e),
         };
 
-        match command {
-            Command::Ehlo(peer_domain) => {
-                // reset data
-                reverse_path = None;
-                forward_path.clear();
-
-                // send reply
-                socket.send_reply(Reply::Ok().with_message(format!(
-                    "{} greets {}{}",
-                    config.domain,
-                    peer_domain,
-                    if config.tls_acceptor.is_some() || config.tls_required {
-                        "\nSTARTTLS"
-                    } else {
-                        ""
-                    }
-                ))).await.unwrap();
-            },
-            Command::Helo(peer_domain) => {
-                // reset data
-                reverse_path = None;
-                forward_path.clear();
-
-                // send reply
-                socket.send_reply(Reply::Ok().with_message(format!(
-                    "{} greets {}",
-                    config.domain,
-                    peer_domain
-                ))).await.unwrap();
-            },
-            Command::Quit => {
-                socket.send_reply(Reply::ServiceClosingTransmissionChannel().with_message("Goodbye!".to_string())).await.unwrap();
-                socket.shutdown().await.unwrap();
-                break;
-            }
-            Command::StartTLS => {
-                if let Some(tls_acceptor) = &config.tls_acceptor {
-                    socket.send_reply(Reply::ServiceReady().with_message("Let's encrypt!".to_string())).await.unwrap();
-                    socket = match socket.accept(tls_acceptor).await {
-                        Ok(s) => s,
-                        Err(e) => {
-                            error!("Failed handshake with client: {}", e);
-                            break;
+        let result = match line {
+            Err(e) => Err(e),
+            Ok(line) => match std::str::from_utf8(&line) {
+                Err(_) => Err(SessionError::InvalidUtf8),
+                Ok(s) => match Command::from_str(s) {
+                    Err(e) => Err(SessionError::Syntax(format!("{:?} -> {:?}", s, e))),
+                    Ok(command) => {
+                        match &command {
+                            // the initial response may hold a password
+                            Command::Auth(mechanism, Some(_)) => {
+                                debug!("Received command: Auth({:?}, ..)", mechanism)
+                            }
+                            _ => debug!("Received command: {:?}", command),
                         }
-                    };
-                    forward_path.clear();
-                    reverse_path = None;
-                } else if config.tls_required {
-                    socket.send_reply(Reply::TlsUnavailable().with_message("TLS required, but unavailable due to temporary reason".to_string())).await.unwrap();
-                } else {
-                    socket.send_reply(Reply::SyntaxError().with_message("Unrecognized command".to_string())).await.unwrap();
-                }
+
+                        match command {
+                            Command::Ehlo(peer_domain) => {
+                                // reset data
+                                session.envelope = None;
+                                session.greeting = Some(Greeting::Ehlo(peer_domain.to_string()));
+
+                                // send reply
+                                let mut message = format!("{} greets {}", domain, peer_domain);
+                                if let Some(max_message_size) = config.max_message_size {
+                                    message.push_str(&format!("\nSIZE {}", max_message_size));
+                                }
+                                if (certificates.is_some() || config.tls_required)
+                                    && !connection.is_encrypted()
+                                {
+                                    message.push_str("\nSTARTTLS");
+                                }
+                                // credentials are only offered over an encrypted connection
+                                if connection.is_encrypted() {
+                                    message.push_str("\nAUTH PLAIN LOGIN");
+                                    if session
+                                        .tls
+                                        .as_ref()
+                                        .map(|tls| tls.client_certificate.is_some())
+                                        .unwrap_or(false)
+                                    {
+                                        message.push_str(" EXTERNAL");
+                                    }
+                                }
+                                connection
+                                    .send_reply(Reply::Ok().with_message(message))
+                                    .await?;
+                                Ok(())
+                            }
+                            Command::Helo(peer_domain) => {
+                                // reset data
+                                session.envelope = None;
+                                session.greeting = Some(Greeting::Helo(peer_domain.to_string()));
+
+                                // send reply
+                                connection
+                                    .send_reply(
+                                        Reply::Ok().with_message(format!(
+                                            "{} greets {}",
+                                            domain, peer_domain
+                                        )),
+                                    )
+                                    .await?;
+                                Ok(())
+                            }
+                            Command::Quit => {
+                                connection
+                                    .send_reply(
+                                        Reply::ServiceClosingTransmissionChannel()
+                                            .with_message("Goodbye!".to_string()),
+                                    )
+                                    .await?;
+                                connection.shutdown().await?;
+                                return Ok(());
+                            }
+                            Command::StartTLS if connection.is_encrypted() => {
+                                Err(SessionError::BadSequence("5.5.1 TLS already active"))
+                            }
+                            Command::StartTLS => {
+                                if let Some(certificates) = &certificates {
+                                    connection
+                                        .send_reply(
+                                            Reply::ServiceReady()
+                                                .with_message("Let's encrypt!".to_string()),
+                                        )
+                                        .await?;
+                                    let (encrypted, hostname) =
+                                        connection.accept(certificates).await?;
+                                    connection = encrypted;
+                                    session.tls = connection.tls_info();
+                                    if let (true, Some(hostname)) = (config.sni_domain, hostname) {
+                                        domain = hostname;
+                                    }
+                                    // forget everything learnt before the handshake, the client must EHLO again (RFC 3207 section 4.2)
+                                    session.greeting = None;
+                                    session.authenticated = None;
+                                    session.envelope = None;
+                                } else if config.tls_required {
+                                    connection.send_reply(Reply::TlsUnavailable().with_message("TLS required, but unavailable due to temporary reason".to_string())).await?;
+                                } else {
+                                    connection
+                                        .send_reply(
+                                            Reply::SyntaxError()
+                                                .with_message("Unrecognized command".to_string()),
+                                        )
+                                        .await?;
+                                }
+                                Ok(())
+                            }
+                            Command::Noop(e) => {
+                                match e {
+                                    Some(e) => connection.send_reply(Reply::Ok().with_message(format!(
+                                        "It is a very sad thing that nowadays there is so little useless information.\nThank you for your {} useless bytes.", e.len(),
+                                    ))).await?,
+                                    None => connection.send_reply(Reply::Ok().with_message("It is better of course to do useless things than to do nothing.".to_string())).await?
+                                }
+                                Ok(())
+                            }
+                            _ if config.tls_required && !connection.is_encrypted() => {
+                                connection
+                                    .send_reply(Reply::TlsRequired().with_message(
+                                        "Must issue a STARTTLS command first".to_string(),
+                                    ))
+                                    .await?;
+                                Ok(())
+                            }
+                            Command::Auth(_, _) if !connection.is_encrypted() => {
+                                connection
+                                    .send_reply(Reply::TlsRequired().with_message(
+                                        "5.7.0 Must issue a STARTTLS command first".to_string(),
+                                    ))
+                                    .await?;
+                                Ok(())
+                            }
+                            Command::Auth(_, _) if session.greeting.is_none() => {
+                                Err(SessionError::BadSequence("5.5.1 EHLO first"))
+                            }
+                            Command::Auth(_, _) if session.authenticated.is_some() => {
+                                Err(SessionError::BadSequence("5.5.1 Already authenticated"))
+                            }
+                            Command::Auth(_, _) if session.envelope.is_some() => {
+                                Err(SessionError::BadSequence(
+                                    "5.5.1 AUTH not allowed during a mail transaction",
+                                ))
+                            }
+                            Command::Auth(mechanism, _)
+                                if !["PLAIN", "LOGIN", "EXTERNAL"]
+                                    .iter()
+                                    .any(|known| mechanism.eq_ignore_ascii_case(known)) =>
+                            {
+                                connection
+                                    .send_reply(
+                                        Reply::CommandParameterNotImplemented().with_message(
+                                            "5.5.4 Unrecognized authentication type".to_string(),
+                                        ),
+                                    )
+                                    .await?;
+                                Ok(())
+                            }
+                            Command::Auth(mechanism, initial_response) => {
+                                let authenticate = match mechanism.to_ascii_uppercase().as_str() {
+                                    "PLAIN" => {
+                                        auth_plain(
+                                            &mut connection,
+                                            &session,
+                                            initial_response,
+                                            event_handler.as_ref(),
+                                        )
+                                        .await?
+                                    }
+                                    "LOGIN" => {
+                                        auth_login(
+                                            &mut connection,
+                                            &session,
+                                            initial_response,
+                                            event_handler.as_ref(),
+                                        )
+                                        .await?
+                                    }
+                                    _ => {
+                                        auth_external(
+                                            &mut connection,
+                                            &session,
+                                            initial_response,
+                                            event_handler.as_ref(),
+                                        )
+                                        .await?
+                                    }
+                                };
+                                match authenticate {
+                                    Ok(identity) => {
+                                        info!(
+                                            "Client {} authenticated as {}",
+                                            session.peer, identity
+                                        );
+                                        session.authenticated = Some(identity);
+                                        connection
+                                            .send_reply(
+                                                Reply::AuthenticationSucceeded().with_message(
+                                                    "2.7.0 Authentication successful".to_string(),
+                                                ),
+                                            )
+                                            .await?;
+                                        Ok(())
+                                    }
+                                    Err(e) => Err(e),
+                                }
+                            }
+                            Command::From(_, _) if session.greeting.is_none() => {
+                                Err(SessionError::BadSequence("5.5.1 EHLO or HELO first"))
+                            }
+                            Command::From(_, _)
+                                if config.auth_required && session.authenticated.is_none() =>
+                            {
+                                connection
+                                    .send_reply(
+                                        Reply::AuthenticationRequired().with_message(
+                                            "5.7.0 Authentication required".to_string(),
+                                        ),
+                                    )
+                                    .await?;
+                                Ok(())
+                            }
+                            Command::From(_, parameters)
+                                if exceeds_max_size(&parameters, config.max_message_size) =>
+                            {
+                                Err(SessionError::MessageTooLarge)
+                            }
+                            Command::From(path, _parameters) => {
+                                // TODO verify identity
+                                session.envelope = Some(Envelope {
+                                    id: queue_id(),
+                                    reverse_path: path
+                                        .map(|Path(_sr, (lp, si))| Address::from_mailbox(&lp, &si)),
+                                    recipients: Vec::new(),
+                                });
+
+                                connection
+                                    .send_reply(
+                                        Reply::Ok().with_message("user recognized".to_string()),
+                                    )
+                                    .await?;
+                                Ok(())
+                            }
+                            Command::To(recipient, _parameters) => {
+                                let may_relay = config
+                                    .relay_policy
+                                    .as_ref()
+                                    .map_or(true, |policy| policy.may_relay(&session));
+                                match &mut session.envelope {
+                                    None => Err(SessionError::BadSequence("5.5.1 MAIL first")),
+                                    Some(envelope) => {
+                                        let recipient =
+                                            Address::from_recipient(&recipient, &config.domain);
+                                        let mailboxes = match &config.aliases {
+                                            _ if !may_relay
+                                                && !config.is_local(&recipient.domain) =>
+                                            {
+                                                Err(SessionError::RelayDenied)
+                                            }
+                                            Some(aliases) => aliases
+                                                .resolve(&recipient)
+                                                .map_err(SessionError::Alias),
+                                            None => Ok(vec![recipient]),
+                                        };
+                                        match mailboxes {
+                                            Err(e) => Err(e),
+                                            Ok(mailboxes) => {
+                                                // a mailbox gets the message once, whatever the detail or case of its address
+                                                let rules = config.local_parts();
+                                                let mut added: Vec<Address> = Vec::new();
+                                                for mailbox in mailboxes {
+                                                    let key = mailbox.mailbox(&rules);
+                                                    if !envelope
+                                                        .recipients
+                                                        .iter()
+                                                        .chain(&added)
+                                                        .any(|other| other.mailbox(&rules) == key)
+                                                    {
+                                                        added.push(mailbox);
+                                                    }
+                                                }
+
+                                                // the first recipient is always accepted, with all its mailboxes
+                                                let total = envelope.recipients.len() + added.len();
+                                                if !envelope.recipients.is_empty()
+                                                    && event_handler
+                                                        .max_recipients()
+                                                        .map_or(false, |max| total > max)
+                                                {
+                                                    Err(SessionError::TooManyRecipients)
+                                                } else {
+                                                    let message = match added.len() {
+                                                        0 => "recipient already added".to_string(),
+                                                        1 => "1 recipient added".to_string(),
+                                                        added => {
+                                                            format!("{} recipients added", added)
+                                                        }
+                                                    };
+                                                    envelope.recipients.extend(added);
+                                                    connection
+                                                        .send_reply(Reply::Ok().with_message(
+                                                            format!(
+                                                                "{}, {} recipients in total",
+                                                                message, total
+                                                            ),
+                                                        ))
+                                                        .await?;
+                                                    Ok(())
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                            Command::Reset => {
+                                session.envelope = None;
+
+                                connection
+                                    .send_reply(Reply::Ok().with_message("OK".to_string()))
+                                    .await?;
+                                Ok(())
+                            }
+                            Command::Verify(user) => match local_address(&config, user.as_ref()) {
+                                Some((aliases, address)) => match aliases.resolve(&address) {
+                                    // an address without alias is a mailbox only if the event handler knows it
+                                    Ok(mailboxes)
+                                        if mailboxes == [address.clone()]
+                                            && !event_handler
+                                                .verify_user(address.to_string())
+                                                .await =>
+                                    {
+                                        connection.send_reply(unknown_user()).await?;
+                                        Ok(())
+                                    }
+                                    Ok(mailboxes) if mailboxes.len() == 1 => {
+                                        connection
+                                            .send_reply(
+                                                Reply::Ok()
+                                                    .with_message(format!("<{}>", mailboxes[0])),
+                                            )
+                                            .await?;
+                                        Ok(())
+                                    }
+                                    Ok(_) => {
+                                        connection
+                                            .send_reply(
+                                                Reply::MailboxNotCorrect().with_message(
+                                                    "5.1.4 User ambiguous".to_string(),
+                                                ),
+                                            )
+                                            .await?;
+                                        Ok(())
+                                    }
+                                    Err(e) => Err(SessionError::Alias(e)),
+                                },
+                                None => {
+                                    if event_handler.verify_user(user.to_string()).await {
+                                        connection
+                                            .send_reply(
+                                                Reply::Ok()
+                                                    .with_message("User recognized".to_string()),
+                                            )
+                                            .await?;
+                                    } else {
+                                        connection
+                                            .send_reply(
+                                                Reply::MailboxNotCorrect()
+                                                    .with_message("User Ambiguous".to_string()),
+                                            )
+                                            .await?;
+                                    }
+                                    Ok(())
+                                }
+                            },
+                            Command::Expand(list_name) => {
+                                match local_address(&config, list_name.as_ref()) {
+                                    Some((aliases, address)) => match aliases.resolve(&address) {
+                                        Ok(mailboxes)
+                                            if mailboxes == [address.clone()]
+                                                && !event_handler
+                                                    .verify_user(address.to_string())
+                                                    .await =>
+                                        {
+                                            connection.send_reply(unknown_user()).await?;
+                                            Ok(())
+                                        }
+                                        Ok(mailboxes) => {
+                                            let mailboxes: Vec<String> = mailboxes
+                                                .iter()
+                                                .map(|mailbox| format!("<{}>", mailbox))
+                                                .collect();
+                                            connection
+                                                .send_reply(
+                                                    Reply::Ok().with_message(mailboxes.join("\n")),
+                                                )
+                                                .await?;
+                                            Ok(())
+                                        }
+                                        Err(e) => Err(SessionError::Alias(e)),
+                                    },
+                                    None => {
+                                        if let Some(mailing_list) = event_handler
+                                            .expand_mailing_list(list_name.to_string())
+                                            .await
+                                        {
+                                            connection
+                                                .send_reply(
+                                                    Reply::Ok()
+                                                        .with_message(mailing_list.join("\n")),
+                                                )
+                                                .await?;
+                                        } else {
+                                            connection
+                                                .send_reply(
+                                                    Reply::ActionNotTaken().with_message(
+                                                        "There is no mailing list with this name"
+                                                            .to_string(),
+                                                    ),
+                                                )
+                                                .await?;
+                                        }
+                                        Ok(())
+                                    }
+                                }
+                            }
+                            Command::Help(e) => {
+                                match e {
+                                    Some(e) => connection.send_reply(Reply::Ok().with_message(format!(
+                                        "Thanks for using this SMTP server! You asked help about {:?}", e.as_ref()
+                                    ))).await?,
+                                    None => connection.send_reply(Reply::Ok().with_message("Thanks for using this SMTP server!".to_string())).await?
+                                }
+                                Ok(())
+                            }
+                            Command::Data
+                                if session
+                                    .envelope
+                                    .as_ref()
+                                    .map(|e| e.recipients.is_empty())
+                                    .unwrap_or(true) =>
+                            {
+                                Err(SessionError::BadSequence("5.5.1 No valid recipients"))
+                            }
+                            Command::Data => {
+                                connection
+                                    .send_reply(
+                                        Reply::StartMailInput()
+                                            .with_message("Go ahead!".to_string()),
+                                    )
+                                    .await?;
+                                debug!("Receiving mail: {:?}", session.envelope);
+                                let mut header = String::new();
+                                if let (true, Some(envelope)) =
+                                    (config.return_path, &session.envelope)
+                                {
+                                    header.push_str(&return_path_header(envelope));
+                                }
+                                header.push_str(&received_header(
+                                    &session,
+                                    &domain,
+                                    &config.server_agent,
+                                    std::time::SystemTime::now(),
+                                ));
+                                let filter = if config.return_path {
+                                    Some(FieldFilter::new("Return-Path"))
+                                } else {
+                                    None
+                                };
+
+                                // the handler reads the message while it is received, and the reply waits for both
+                                let (mut writer, mut reader) = message::channel();
+                                let reading = async {
+                                    writer.write(header.as_bytes()).await;
+                                    read_message(
+                                        &mut connection,
+                                        writer,
+                                        config.max_message_size,
+                                        filter,
+                                    )
+                                    .await
+                                };
+                                let delivering = async {
+                                    let result = event_handler
+                                        .on_message_stream(&session, &mut reader)
+                                        .await;
+                                    drop(reader);
+                                    result
+                                };
+                                let (read, delivered) = futures::join!(reading, delivering);
+                                let result = match (read?, delivered) {
+                                    (Err(e), _) => Err(e),
+                                    (Ok(()), Err(e)) => Err(e),
+                                    (Ok(()), Ok(())) => {
+                                        connection.send_reply(Reply::Ok().with_message("Status confirmed, all bytes are down and the mail is secure.".to_string())).await?;
+                                        Ok(())
+                                    }
+                                };
+                                session.envelope = None;
+                                result
+                            }
+                        }
+                    }
+                },
             },
-            Command::Noop(e) => {
-                match e {
-                    Some(e) => socket.send_reply(Reply::Ok().with_message(format!(
-                        "It is a very sad thing that nowadays there is so little useless information.\nThank you for your {} useless bytes.", e.len(),
-                    ))).await.unwrap(),
-                    None => socket.send_reply(Reply::Ok().with_message("It is better of course to do useless things than to do nothing.".to_string())).await.unwrap()
-                }
+        };
+
+        if let Err(e) = result {
+            let reply = match e.reply() {
+                Some(reply) => reply,
+                None => return Err(e),
+            };
+            warn!("Error with client {}: {}", session.peer, e);
+            errors += 1;
+            if errors >= MAX_ERRORS {
+                connection
+                    .send_reply(
+                        Reply::ServiceUnavailable()
+                            .with_message("4.7.0 Too many errors, closing connection".to_string()),
+                    )
+                    .await?;
+                connection.shutdown().await?;
+                return Err(SessionError::TooManyErrors);
             }
-            _ if config.tls_required && !socket.is_encrypted() => {
-                socket.send_reply(Reply::TlsRequired().with_message("Must issue a STARTTLS command first".to_string())).await.unwrap();
+            connection.send_reply(reply).await?;
+        }
+    }
+}
+
+/// SASL EXTERNAL (RFC 4422 appendix A): the client authenticates with the certificate it presented during the TLS handshake.
+/// Returns the identity the [EventHandler] maps the certificate to.
+///
+/// The outer result is an error if the session cannot go on, the inner one if the authentication failed.
+async fn auth_external<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    session: &Session,
+    initial_response: Option<&str>,
+    event_handler: &dyn EventHandler,
+) -> Result<Result<String, SessionError>, SessionError> {
+    let authorization_identity = match auth_response(connection, initial_response, "").await? {
+        Ok(identity) if identity.is_empty() => None,
+        Ok(identity) => Some(identity),
+        Err(e) => return Ok(Err(e)),
+    };
+    if session
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.client_certificate.as_ref())
+        .is_none()
+    {
+        return Ok(Err(SessionError::AuthenticationFailed));
+    }
+
+    match event_handler
+        .authenticate_external(session, authorization_identity)
+        .await
+    {
+        Some(identity) => Ok(Ok(identity)),
+        None => Ok(Err(SessionError::AuthenticationFailed)),
+    }
+}
+
+/// SASL PLAIN (RFC 4616): the client sends its authorization identity, username and password in one response.
+/// Returns the identity the [EventHandler] maps the credentials to.
+///
+/// The outer result is an error if the session cannot go on, the inner one if the authentication failed.
+async fn auth_plain<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    session: &Session,
+    initial_response: Option<&str>,
+    event_handler: &dyn EventHandler,
+) -> Result<Result<String, SessionError>, SessionError> {
+    let response = match auth_response(connection, initial_response, "").await? {
+        Ok(response) => response,
+        Err(e) => return Ok(Err(e)),
+    };
+    let mut fields = response.split('\0');
+    let (authorization_identity, username, password) =
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(identity), Some(username), Some(password), None)
+                if !username.is_empty() && !password.is_empty() =>
+            {
+                (identity, username, password)
             }
-            Command::From(path, _parameters) => {
-                if let Some(Path(_sr,(lp, si))) = path {
-                    // TODO verify identity
-                    reverse_path = Some((lp.to_owned(), si.to_owned()));
-                    forward_path.clear();
+            _ => return Ok(Err(SessionError::InvalidAuthResponse)),
+        };
+    let authorization_identity = if authorization_identity.is_empty() {
+        None
+    } else {
+        Some(authorization_identity.to_string())
+    };
 
-                    socket.send_reply(Reply::Ok().with_message("user recognized".to_string())).await.unwrap();
-                } else {
-                    socket.send_reply(Reply::UserNotLocal().with_message("please specify an existing user".to_string())).await.unwrap();
-                }
+    match event_handler
+        .authenticate(
+            session,
+            authorization_identity,
+            username.to_string(),
+            password.to_string(),
+        )
+        .await
+    {
+        Some(identity) => Ok(Ok(identity)),
+        None => Ok(Err(SessionError::AuthenticationFailed)),
+    }
+}
+
+/// SASL LOGIN: the client sends its username and its password in two responses.
+/// It is obsolete, but still the only mechanism of some clients.
+///
+/// The outer result is an error if the session cannot go on, the inner one if the authentication failed.
+async fn auth_login<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    session: &Session,
+    initial_response: Option<&str>,
+    event_handler: &dyn EventHandler,
+) -> Result<Result<String, SessionError>, SessionError> {
+    let username = match auth_response(connection, initial_response, "Username:").await? {
+        Ok(username) => username,
+        Err(e) => return Ok(Err(e)),
+    };
+    let password = match auth_response(connection, None, "Password:").await? {
+        Ok(password) => password,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    match event_handler
+        .authenticate(session, None, username, password)
+        .await
+    {
+        Some(identity) => Ok(Ok(identity)),
+        None => Ok(Err(SessionError::AuthenticationFailed)),
+    }
+}
+
+/// The decoded response of the client: the initial response of AUTH if there is one,
+/// else the answer to a `334` reply with the `challenge`.
+///
+/// The outer result is an error if the session cannot go on, the inner one if the client cancelled the exchange
+/// or sent an invalid response.
+async fn auth_response<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    initial_response: Option<&str>,
+    challenge: &str,
+) -> Result<Result<String, SessionError>, SessionError> {
+    let response = match initial_response {
+        // a single "=" is an empty initial response (RFC 4954 section 4)
+        Some("=") => String::new(),
+        Some(response) => response.to_string(),
+        None => {
+            connection
+                .send_reply(Reply::ServerChallenge().with_message(base64::encode(challenge)))
+                .await?;
+            let line = connection
+                .read_line(MAX_COMMAND_LINE_LENGTH)
+                .await?
+                .ok_or(SessionError::ConnectionClosed)?;
+            let line = String::from_utf8_lossy(&line);
+            let response = line.trim_end_matches("\r\n");
+            if response == "*" {
+                return Ok(Err(SessionError::AuthenticationCancelled));
             }
-            Command::To(recipient, _parameters) => {
-                let recipient = recipient.into();
-                if !forward_path.contains(&recipient) {
-                    forward_path.push(recipient.to_owned());
-
-                    socket.send_reply(Reply::Ok().with_message(format!(
-                        "1 recipient added, {} recipients in total", forward_path.len()
-                    ))).await.unwrap();
-                } else {
-                    socket.send_reply(Reply::Ok().with_message(format!(
-                        "recipient already added, {} recipients in total", forward_path.len()
-                    ))).await.unwrap();
-                }
-            },
-            Command::Reset => {
-                forward_path.clear();
-                reverse_path = None;
+            response.to_string()
+        }
+    };
+
+    match base64::decode(&response).map(String::from_utf8) {
+        Ok(Ok(response)) => Ok(Ok(response)),
+        _ => Ok(Err(SessionError::InvalidAuthResponse)),
+    }
+}
 
-                socket.send_reply(Reply::Ok().with_message("OK".to_string())).await.unwrap();
+/// Read the lines of a message until the terminating `.`, remove the dots added for transparency (RFC 5321 section 4.5.2),
+/// and give them to the `writer`, without the fields the `filter` removes.
+///
+/// The outer result is an error if the session cannot go on, the inner one if the message must be refused.
+/// In both cases the reader fails instead of reaching the end of the message.
+/// The whole message is read even if it is refused, so that the client can continue.
+async fn read_message<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    mut writer: MessageWriter,
+    max_message_size: Option<usize>,
+    mut filter: Option<FieldFilter<'_>>,
+) -> Result<Result<(), SessionError>, SessionError> {
+    let mut size = 0;
+    let mut error = None;
+
+    loop {
+        let line = match connection.read_line(MAX_TEXT_LINE_LENGTH).await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                writer.fail("connection closed").await;
+                return Err(SessionError::ConnectionClosed);
             }
-            Command::Verify(user) => {
-                if event_handler.verify_user(user.to_string()).await {
-                    socket.send_reply(Reply::Ok().with_message("User recognized".to_string())).await.unwrap();
-                } else {
-                    socket.send_reply(Reply::MailboxNotCorrect().with_message("User Ambiguous".to_string())).await.unwrap();
+            Err(SessionError::LineTooLong) => {
+                if error.is_none() {
+                    writer.fail("line too long").await;
                 }
+                error = Some(SessionError::LineTooLong);
+                continue;
             }
-            Command::Expand(list_name) => {
-                if let Some(mailing_list) = event_handler.expand_mailing_list(list_name.to_string()).await {
-                    socket.send_reply(Reply::Ok().with_message(mailing_list.join("\n").to_string())).await.unwrap();
-                } else {
-                    socket.send_reply(Reply::ActionNotTaken().with_message("There is no mailing list with this name".to_string())).await.unwrap();
-                }
+            Err(e) => {
+                writer.fail(&e.to_string()).await;
+                return Err(e);
             }
-            Command::Help(e) => {
-                match e {
-                    Some(e) => socket.send_reply(Reply::Ok().with_message(format!(
-                        "Thanks for using this SMTP server! You asked help about {:?}", e.as_ref()
-                    ))).await.unwrap(),
-                    None => socket.send_reply(Reply::Ok().with_message("Thanks for using this SMTP server!".to_string())).await.unwrap()
-                }
+        };
+
+        if &line[..] == b".\r\n" {
+            break;
+        }
+        let line = match line.strip_prefix(b".") {
+            Some(line) => line,
+            None => &line[..],
+        };
+
+        size += line.len();
+        if error.is_none() && matches!(max_message_size, Some(max) if size > max) {
+            writer.fail("message too large").await;
+            error = Some(SessionError::MessageTooLarge);
+        }
+        if error.is_none() && filter.as_mut().map(|f| f.keep(line)).unwrap_or(true) {
+            writer.write(line).await;
+        }
+    }
+
+    match error {
+        Some(e) => Ok(Err(e)),
+        None => Ok(Ok(())),
+    }
+}
+
+/// The alias table and the address given to VRFY or EXPN, if it is an address of a virtual domain of the table.
+fn local_address<'a>(config: &'a Config, argument: &str) -> Option<(&'a AliasTable, Address)> {
+    let aliases = config.aliases.as_deref()?;
+    let argument = argument.trim();
+    let argument = argument
+        .strip_prefix('<')
+        .and_then(|a| a.strip_suffix('>'))
+        .unwrap_or(argument);
+    let address = Address::parse(argument)?;
+    match aliases.is_local(&address.domain) {
+        true => Some((aliases, address)),
+        false => None,
+    }
+}
+
+/// The reply to VRFY and EXPN for an address of a local domain which is neither an alias nor a known mailbox.
+fn unknown_user() -> Reply {
+    Reply::ActionNotTaken().with_message("5.1.1 User unknown".to_string())
+}
+
+/// Whether the SIZE parameter of a MAIL command (RFC 1870) announces a message bigger than the limit.
+fn exceeds_max_size(parameters: &[(&str, Option<&str>)], max_message_size: Option<usize>) -> bool {
+    let max_message_size = match max_message_size {
+        Some(max_message_size) => max_message_size,
+        None => return false,
+    };
+    parameters
+        .iter()
+        .filter(|(keyword, _)| keyword.eq_ignore_ascii_case("SIZE"))
+        .filter_map(|(_, value)| value.and_then(|v| v.parse::<usize>().ok()))
+        .any(|size| size > max_message_size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dns::{test::StaticResolver, Blocklists};
+    use crate::message::MessageReader;
+    use crate::policy::RelayPolicy;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    const EMAIL: &[u8] =
+        b"Date: Mon, 19 Oct 2026 10:00:00 +0000\r\nFrom: alice@example.com\r\n\r\nHello\r\n.\r\n";
+
+    /// The configuration of the tests, resolving the address of the client without network.
+    fn config() -> Config {
+        let ip = "192.0.2.1".parse().unwrap();
+        let mut resolver = StaticResolver::default();
+        resolver
+            .names
+            .insert(ip, vec!["client.example.com".to_string()]);
+        resolver
+            .addresses
+            .insert("client.example.com".to_string(), vec![ip]);
+
+        let mut config = Config::new("example.com".to_string());
+        config.resolver = Arc::new(resolver);
+        config
+    }
+
+    #[derive(Default)]
+    struct TestHandler {
+        mails: Mutex<Vec<String>>,
+        traces: Mutex<Vec<String>>,
+        sessions: Mutex<Vec<Session>>,
+        refuse: bool,
+        max_recipients: Option<usize>,
+    }
+
+    #[async_trait::async_trait]
+    impl EventHandler for TestHandler {
+        async fn on_mail<'b>(
+            &self,
+            session: &Session,
+            email: std::pin::Pin<&email_parser::email::Email<'b>>,
+        ) -> Result<(), String> {
+            if self.refuse {
+                return Err("refused".to_string());
             }
-            Command::Data => {
-                socket.send_reply(Reply::StartMailInput().with_message("Go ahead!".to_string())).await.unwrap();
-                let mut b = BytesMut::new();
-                loop {
-                    socket.read_buf(&mut b).await.unwrap();
-                    if b.ends_with(b"\r\n.\r\n") {
-                        break;
+            self.sessions.lock().unwrap().push(session.clone());
+            self.mails
+                .lock()
+                .unwrap()
+                .push(format!("{:?}", email.as_ref().body));
+            self.traces
+                .lock()
+                .unwrap()
+                .push(format!("{:?}", email.as_ref().trace));
+            Ok(())
+        }
+
+        fn max_recipients(&self) -> Option<usize> {
+            self.max_recipients
+        }
+
+        async fn verify_user(&self, name: String) -> bool {
+            name == "carol@example.com"
+        }
+
+        async fn authenticate(
+            &self,
+            _session: &Session,
+            authorization_identity: Option<String>,
+            username: String,
+            password: String,
+        ) -> Option<String> {
+            match (
+                authorization_identity.as_deref(),
+                username.as_str(),
+                password.as_str(),
+            ) {
+                (None, "alice", "secret") | (Some("alice"), "alice", "secret") => Some(username),
+                _ => None,
+            }
+        }
+
+        async fn authenticate_external(
+            &self,
+            session: &Session,
+            authorization_identity: Option<String>,
+        ) -> Option<String> {
+            session.tls.as_ref()?.client_certificate.as_ref()?;
+            match authorization_identity.as_deref() {
+                None | Some("relay") => Some("relay".to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    struct Client<S = DuplexStream> {
+        stream: S,
+        server: tokio::task::JoinHandle<()>,
+    }
+
+    impl Client {
+        fn connect(config: Config, event_handler: Arc<TestHandler>) -> Client {
+            let (stream, server) = tokio::io::duplex(64 * 1024);
+            let session = Session::new(Peer::Tcp("192.0.2.1:4321".parse().unwrap()), "test");
+            let server = tokio::spawn(handle_client(
+                server,
+                session,
+                Arc::new(config),
+                event_handler,
+            ));
+            Client { stream, server }
+        }
+
+        /// Start the TLS handshake, once the server accepted STARTTLS, and check the certificate is valid for `domain`.
+        async fn starttls(
+            self,
+            domain: &str,
+        ) -> Client<crate::tls::test::ClientStream<DuplexStream>> {
+            let stream = crate::tls::test::connect(domain, self.stream, true).await;
+            Client {
+                stream,
+                server: self.server,
+            }
+        }
+
+        /// Like [starttls](Client::starttls), presenting the test client certificate.
+        #[cfg(feature = "rustls")]
+        async fn starttls_with_certificate(
+            self,
+            domain: &str,
+        ) -> Client<crate::tls::test::ClientStream<DuplexStream>> {
+            let stream = crate::tls::test::connect_with_certificate(domain, self.stream).await;
+            Client {
+                stream,
+                server: self.server,
+            }
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+        async fn send(&mut self, data: &[u8]) {
+            self.stream.write_all(data).await.unwrap();
+        }
+
+        /// Read a complete (possibly multiline) reply.
+        async fn reply_text(&mut self) -> String {
+            let mut reply = Vec::new();
+            loop {
+                let n = self.stream.read_buf(&mut reply).await.unwrap();
+                assert!(n > 0, "connection closed while waiting for a reply");
+                let text = String::from_utf8(reply.clone()).unwrap();
+                if let Some(last_line) = text
+                    .strip_suffix("\r\n")
+                    .and_then(|t| t.rsplit("\r\n").next())
+                {
+                    if last_line.as_bytes().get(3) == Some(&b' ') {
+                        return text;
                     }
                 }
-                b.truncate(b.len() - 3);
-                use email_parser::prelude::*;
+            }
+        }
 
-                let email = Email::parse(&b).unwrap();
+        /// Read a complete reply and return its code.
+        async fn reply(&mut self) -> usize {
+            self.reply_text().await[..3].parse().unwrap()
+        }
 
-                match event_handler.on_mail(std::pin::Pin::new(&email)).await {
-                    Ok(()) => socket.send_reply(Reply::Ok().with_message("Status confirmed, all bytes are down and the mail is secure.".to_string())).await.unwrap(),
-                    Err(e) => socket.send_reply(Reply::ActionAborted().with_message(format!(
-                        "Mail not delivered: {}", e
-                    ))).await.unwrap()
-                }
-                forward_path = Vec::new();
+        async fn command(&mut self, data: &[u8]) -> usize {
+            self.send(data).await;
+            self.reply().await
+        }
+
+        /// Wait for the server to close the connection and make sure the session did not panic.
+        async fn closed(mut self) {
+            let mut rest = Vec::new();
+            self.stream.read_to_end(&mut rest).await.unwrap();
+            self.server.await.expect("the session panicked");
+        }
+    }
+
+    async fn start_transaction(client: &mut Client) {
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"EHLO client.example.com\r\n").await, 250);
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            250
+        );
+        assert_eq!(client.command(b"RCPT TO:<bob@example.com>\r\n").await, 250);
+    }
 
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_delivery() {
+        let handler = Arc::new(TestHandler::default());
+        let mut client = Client::connect(config(), Arc::clone(&handler));
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+        assert_eq!(handler.mails.lock().unwrap().len(), 1);
+
+        // the message starts with a Received header
+        let session = &handler.sessions.lock().unwrap()[0];
+        assert_eq!(session.reverse_dns.as_deref(), Some("client.example.com"));
+        let trace = &handler.traces.lock().unwrap()[0];
+        assert!(trace.contains("client.example.com"));
+        assert!(trace.contains(&session.envelope.as_ref().unwrap().id));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_return_path() {
+        let handler = Arc::new(TestHandler::default());
+        let mut config = config();
+        config.return_path = true;
+        let mut client = Client::connect(config, Arc::clone(&handler));
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        client.send(b"Return-Path: <forged@example.net>\r\n").await;
+        assert_eq!(client.command(EMAIL).await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let trace = &handler.traces.lock().unwrap()[0];
+        assert!(trace.contains("alice"));
+        assert!(!trace.contains("forged"));
+    }
+
+    /// A handler storing the raw messages, even those that cannot be parsed.
+    #[derive(Default)]
+    struct RawHandler {
+        messages: Mutex<Vec<Vec<u8>>>,
+    }
 
+    #[async_trait::async_trait]
+    impl EventHandler for RawHandler {
+        async fn on_message(&self, _session: &Session, message: &[u8]) -> Result<(), SessionError> {
+            self.messages.lock().unwrap().push(message.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_aliases() {
+        let handler = Arc::new(TestHandler::default());
+        let mut config = config();
+        config.aliases = Some(Arc::new(
+            AliasTable::parse(
+                "domain example.com\n\
+                 postmaster@example.com: alice@example.com\n\
+                 team@example.com: alice@example.com, bob@example.com\n\
+                 loop@example.com: loop@example.net, loop2@example.com\n\
+                 loop2@example.com: loop@example.com\n",
+            )
+            .unwrap(),
+        ));
+        let mut client = Client::connect(config, Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"EHLO client.example.com\r\n").await, 250);
+        assert_eq!(
+            client.command(b"MAIL FROM:<carol@example.net>\r\n").await,
+            250
+        );
+        client.send(b"RCPT TO:<team@example.com>\r\n").await;
+        assert!(client
+            .reply_text()
+            .await
+            .starts_with("250 2 recipients added"));
+        client.send(b"RCPT TO:<postmaster>\r\n").await;
+        assert!(client
+            .reply_text()
+            .await
+            .starts_with("250 recipient already added"));
+        assert_eq!(client.command(b"RCPT TO:<loop@example.com>\r\n").await, 550);
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 250);
+
+        client.send(b"VRFY postmaster@example.com\r\n").await;
+        assert_eq!(client.reply_text().await, "250 <alice@example.com>\r\n");
+        assert_eq!(client.command(b"VRFY <team@example.com>\r\n").await, 553);
+        client.send(b"EXPN team@example.com\r\n").await;
+        assert_eq!(
+            client.reply_text().await,
+            "250-<alice@example.com>\r\n250 <bob@example.com>\r\n"
+        );
+        // the mailboxes without alias are those the event handler knows
+        client.send(b"VRFY carol@example.com\r\n").await;
+        assert_eq!(client.reply_text().await, "250 <carol@example.com>\r\n");
+        client.send(b"VRFY nobody@example.com\r\n").await;
+        assert_eq!(client.reply_text().await, "550 5.1.1 User unknown\r\n");
+        assert_eq!(client.command(b"EXPN nobody@example.com\r\n").await, 550);
+        // other domains are left to the event handler
+        assert_eq!(client.command(b"EXPN team@example.net\r\n").await, 550);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let sessions = handler.sessions.lock().unwrap();
+        let recipients: Vec<String> = sessions[0]
+            .envelope
+            .as_ref()
+            .unwrap()
+            .recipients
+            .iter()
+            .map(Address::to_string)
+            .collect();
+        assert_eq!(recipients, ["alice@example.com", "bob@example.com"]);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_relay_policy() {
+        let handler = Arc::new(TestHandler::default());
+        let mut restricted = config();
+        restricted.aliases = Some(Arc::new(AliasTable::parse("domain example.net\n").unwrap()));
+        restricted.relay_policy = Some(Arc::new(
+            RelayPolicy::new().domain("example.org").authenticated(true),
+        ));
+        let mut client = Client::connect(restricted.clone(), Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"EHLO client.example.com\r\n").await, 250);
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.org>\r\n").await,
+            250
+        );
+        assert_eq!(client.command(b"RCPT TO:<bob@example.com>\r\n").await, 250);
+        assert_eq!(client.command(b"RCPT TO:<bob@EXAMPLE.org>\r\n").await, 250);
+        assert_eq!(client.command(b"RCPT TO:<bob@example.net>\r\n").await, 250);
+        assert_eq!(client.command(b"RCPT TO:<postmaster>\r\n").await, 250);
+        client.send(b"RCPT TO:<bob@example.info>\r\n").await;
+        assert_eq!(
+            client.reply_text().await,
+            "554 5.7.1 Relay access denied\r\n"
+        );
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let recipients = handler.sessions.lock().unwrap()[0]
+            .envelope
+            .as_ref()
+            .unwrap()
+            .recipients
+            .clone();
+        assert!(recipients
+            .iter()
+            .all(|recipient| recipient.domain != "example.info"));
+
+        // the clients of the relay networks may send to any domain
+        restricted.relay_policy = Some(Arc::new(
+            RelayPolicy::new().network("192.0.2.0/24".parse().unwrap()),
+        ));
+        let mut client = Client::connect(restricted, Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"EHLO client.example.com\r\n").await, 250);
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.org>\r\n").await,
+            250
+        );
+        assert_eq!(client.command(b"RCPT TO:<bob@example.info>\r\n").await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_blocklists() {
+        let mut resolver = StaticResolver::default();
+        resolver.addresses.insert(
+            "1.2.0.192.zen.example.org".to_string(),
+            vec!["127.0.0.2".parse().unwrap()],
+        );
+        resolver.addresses.insert(
+            "1.2.0.192.bl.example.net".to_string(),
+            vec!["127.0.0.3".parse().unwrap()],
+        );
+        let mut listed = config();
+        listed.resolver = Arc::new(resolver);
+        listed.blocklists = Some(Arc::new(
+            Blocklists::new()
+                .list("zen.example.org", 10)
+                .list("bl.example.net", 2)
+                .threshold(12),
+        ));
+        let mut client = Client::connect(listed.clone(), Arc::new(TestHandler::default()));
+        assert_eq!(
+            client.reply_text().await,
+            "554 example.com 5.7.1 Service unavailable; client [192.0.2.1] blocked using zen.example.org, bl.example.net\r\n"
+        );
+        client.closed().await;
+
+        // below the threshold, the score is given to the event handler
+        let handler = Arc::new(TestHandler::default());
+        listed.blocklists = Some(Arc::new(
+            Blocklists::new()
+                .list("zen.example.org", 10)
+                .list("other.example.com", 5)
+                .threshold(12),
+        ));
+        let mut client = Client::connect(listed, Arc::clone(&handler));
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let session = handler.sessions.lock().unwrap()[0].clone();
+        assert_eq!(session.blocklists, ["zen.example.org"]);
+        assert_eq!(session.blocklist_score, 10);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_raw_message() {
+        let handler = Arc::new(RawHandler::default());
+        let (stream, server) = tokio::io::duplex(64 * 1024);
+        let session = Session::new(Peer::Tcp("192.0.2.1:4321".parse().unwrap()), "test");
+        let server = tokio::spawn(handle_client(
+            server,
+            session,
+            Arc::new(config()),
+            Arc::clone(&handler) as Arc<dyn EventHandler>,
+        ));
+        let mut client = Client { stream, server };
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(
+            client
+                .command(b"\xff\xfe not an email\r\n..leading dot\r\n.\r\n")
+                .await,
+            250
+        );
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let message = &handler.messages.lock().unwrap()[0];
+        assert!(message.starts_with(
+            b"Received: from client.example.com (client.example.com [192.0.2.1])\r\n"
+        ));
+        assert!(message.ends_with(b" +0000\r\n\xff\xfe not an email\r\n.leading dot\r\n"));
+    }
+
+    /// Counts the bytes of each message while it is received.
+    #[derive(Default)]
+    struct StreamHandler {
+        sizes: Mutex<Vec<Result<usize, String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EventHandler for StreamHandler {
+        async fn on_message_stream(
+            &self,
+            _session: &Session,
+            message: &mut MessageReader,
+        ) -> Result<(), SessionError> {
+            let mut buffer = [0; 1000];
+            let mut size = 0;
+            let result = loop {
+                match message.read(&mut buffer).await {
+                    Ok(0) => break Ok(size),
+                    Ok(n) => size += n,
+                    Err(e) => break Err(e.to_string()),
+                }
+            };
+            let failed = result.is_err();
+            self.sizes.lock().unwrap().push(result);
+            match failed {
+                true => Err(SessionError::Handler("truncated message".to_string())),
+                false => Ok(()),
             }
         }
     }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_message_stream() {
+        let handler = Arc::new(StreamHandler::default());
+        let connect = |config: Config| {
+            let (stream, server) = tokio::io::duplex(64 * 1024);
+            let session = Session::new(Peer::Tcp("192.0.2.1:4321".parse().unwrap()), "test");
+            let server = tokio::spawn(handle_client(
+                server,
+                session,
+                Arc::new(config),
+                Arc::clone(&handler) as Arc<dyn EventHandler>,
+            ));
+            Client { stream, server }
+        };
+
+        // much more than the buffers between the client and the handler
+        let mut client = connect(config());
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        for _ in 0..10_000 {
+            client.send(&[b'a'; 98]).await;
+            client.send(b"\r\n").await;
+        }
+        assert_eq!(client.command(b".\r\n").await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let mut limited = config();
+        limited.max_message_size = Some(100);
+        let mut client = connect(limited);
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        client.send(&[b'a'; 500]).await;
+        assert_eq!(client.command(b"\r\n.\r\n").await, 552);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let mut client = connect(config());
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        client.send(b"Subject: cut\r\n").await;
+        client.stream.shutdown().await.unwrap();
+        client.closed().await;
+
+        let sizes = handler.sizes.lock().unwrap();
+        assert!(matches!(sizes[0], Ok(size) if size > 1_000_000));
+        assert!(sizes[1].is_err());
+        assert!(sizes[2].is_err());
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_handler_failure() {
+        let handler = Arc::new(TestHandler {
+            refuse: true,
+            ..TestHandler::default()
+        });
+        let mut client = Client::connect(config(), handler);
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 451);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_disconnections() {
+        let mut client = Client::connect(config(), Arc::default());
+        start_transaction(&mut client).await;
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        client
+            .send(b"Date: Mon, 19 Oct 2026 10:00:00 +0000\r\n")
+            .await;
+        client.stream.shutdown().await.unwrap();
+        client.closed().await;
+
+        let mut client = Client::connect(config(), Arc::default());
+        assert_eq!(client.reply().await, 220);
+        client.send(b"EHLO").await;
+        client.stream.shutdown().await.unwrap();
+        client.closed().await;
+    }
+
+    /// Run a whole session on a runtime with only a timer, with everything the client sends written at once.
+    /// Works under Miri, unlike the tests above which need the tokio I/O driver.
+    fn replay(config: Config, event_handler: Arc<TestHandler>, input: &[u8]) -> Vec<usize> {
+        let (mut client, server) = tokio::io::duplex(input.len() + 64 * 1024);
+        let session = Session::new(Peer::Tcp("192.0.2.1:4321".parse().unwrap()), "test");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async move {
+            client.write_all(input).await.unwrap();
+            client.shutdown().await.unwrap();
+            handle_client(server, session, Arc::new(config), event_handler).await;
+
+            let mut output = String::new();
+            client.read_to_string(&mut output).await.unwrap();
+            output
+                .split("\r\n")
+                .filter(|line| line.as_bytes().get(3) == Some(&b' '))
+                .map(|line| line[..3].parse().unwrap())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let handler = Arc::new(TestHandler::default());
+        let mut input = b"EHLO client.example.com\r\nMAIL FROM:<>\r\nRCPT TO:<bob@example.com>\r\nRCPT TO:<Postmaster>\r\nDATA\r\n".to_vec();
+        input.extend_from_slice(EMAIL);
+        input.extend_from_slice(b"RCPT TO:<bob@example.com>\r\nQUIT\r\n");
+        assert_eq!(
+            replay(config(), Arc::clone(&handler), &input),
+            vec![220, 250, 250, 250, 250, 354, 250, 503, 221]
+        );
+        assert_eq!(handler.mails.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_recipients() {
+        let handler = Arc::new(TestHandler::default());
+        let mut input =
+            b"EHLO client.example.com\r\nMAIL FROM:<>\r\nRCPT TO:<Bob+lists@example.com>\r\n\
+                          RCPT TO:<bob@EXAMPLE.com>\r\nRCPT TO:<carol@example.com>\r\nDATA\r\n"
+                .to_vec();
+        input.extend_from_slice(EMAIL);
+        input.extend_from_slice(b"QUIT\r\n");
+        assert_eq!(
+            replay(config(), Arc::clone(&handler), &input),
+            vec![220, 250, 250, 250, 250, 250, 354, 250, 221]
+        );
+
+        let sessions = handler.sessions.lock().unwrap();
+        let recipients: Vec<String> = sessions[0]
+            .envelope
+            .as_ref()
+            .unwrap()
+            .recipients
+            .iter()
+            .map(Address::to_string)
+            .collect();
+        assert_eq!(recipients, ["Bob+lists@example.com", "carol@example.com"]);
+    }
+
+    #[test]
+    fn test_max_recipients() {
+        let handler = Arc::new(TestHandler {
+            max_recipients: Some(1),
+            ..TestHandler::default()
+        });
+        let mut input = b"EHLO client.example.com\r\nMAIL FROM:<>\r\nRCPT TO:<bob@example.com>\r\n\
+                          RCPT TO:<Bob+lists@example.com>\r\nRCPT TO:<carol@example.com>\r\nDATA\r\n"
+            .to_vec();
+        input.extend_from_slice(EMAIL);
+        input.extend_from_slice(b"MAIL FROM:<>\r\nRCPT TO:<carol@example.com>\r\nDATA\r\n");
+        input.extend_from_slice(EMAIL);
+        input.extend_from_slice(b"QUIT\r\n");
+        assert_eq!(
+            replay(config(), Arc::clone(&handler), &input),
+            vec![220, 250, 250, 250, 250, 452, 354, 250, 250, 250, 354, 250, 221]
+        );
+        assert_eq!(handler.mails.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let input = b"\xff\xfe\xfd\r\n\x00\x01\x02\x03\r\nMAIL FROM:<<<>>>\r\nEHLO [300.1.1.1]\r\n\
+                      MAIL FROM:<alice@example.com>\r\nDATA\r\nNOOP\r\nQUIT\r\n";
+        assert_eq!(
+            replay(config(), Arc::default(), input),
+            vec![220, 500, 500, 500, 500, 503, 503, 250, 221]
+        );
+    }
+
+    #[test]
+    fn test_too_many_errors() {
+        let input = b"GARBAGE\r\n".repeat(MAX_ERRORS + 1);
+        let mut expected = vec![500; MAX_ERRORS + 1];
+        expected[0] = 220;
+        expected[MAX_ERRORS] = 421;
+        assert_eq!(replay(config(), Arc::default(), &input), expected);
+    }
+
+    #[test]
+    fn test_invalid_message() {
+        let handler = Arc::new(TestHandler::default());
+        let input = b"EHLO client.example.com\r\nMAIL FROM:<alice@example.com>\r\nRCPT TO:<bob@example.com>\r\n\
+                      DATA\r\n\xff\xfe not an email\r\n.\r\nNOOP\r\nQUIT\r\n";
+        assert_eq!(
+            replay(config(), Arc::clone(&handler), input),
+            vec![220, 250, 250, 250, 354, 554, 250, 221]
+        );
+        assert!(handler.mails.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_message_too_large() {
+        let mut limited = config();
+        limited.max_message_size = Some(100);
+        let mut input = b"EHLO client.example.com\r\nMAIL FROM:<alice@example.com>\r\nRCPT TO:<bob@example.com>\r\n\
+                          MAIL FROM:<alice@example.com> SIZE=1000\r\nDATA\r\n"
+            .to_vec();
+        input.extend_from_slice(&[b'a'; 500]);
+        input.extend_from_slice(b"\r\n.\r\nQUIT\r\n");
+        assert_eq!(
+            replay(limited, Arc::default(), &input),
+            vec![220, 250, 250, 250, 552, 354, 552, 221]
+        );
+    }
+
+    #[test]
+    fn test_long_lines() {
+        let handler = Arc::new(TestHandler::default());
+        let mut input = b"EHLO client.example.com\r\nNOOP ".to_vec();
+        input.extend_from_slice(&[b'a'; MAX_COMMAND_LINE_LENGTH]);
+        input.extend_from_slice(
+            b"\r\nMAIL FROM:<alice@example.com>\r\nRCPT TO:<bob@example.com>\r\nDATA\r\n",
+        );
+        input.extend_from_slice(&[b'a'; MAX_TEXT_LINE_LENGTH]);
+        input.extend_from_slice(b"\r\n");
+        input.extend_from_slice(EMAIL);
+        input.extend_from_slice(b"QUIT\r\n");
+        assert_eq!(
+            replay(config(), Arc::clone(&handler), &input),
+            vec![220, 250, 500, 250, 250, 354, 500, 221]
+        );
+        assert!(handler.mails.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_split_commands() {
+        let handler = Arc::new(TestHandler::default());
+        let mut client = Client::connect(config(), Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        client.send(b"EH").await;
+        client.send(b"LO client.exa").await;
+        client.send(b"mple.com\r").await;
+        assert_eq!(client.command(b"\n").await, 250);
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            250
+        );
+        assert_eq!(client.command(b"RCPT TO:<bob@example.com>\r\n").await, 250);
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        for chunk in EMAIL.chunks(7) {
+            client.send(chunk).await;
+        }
+        assert_eq!(client.reply().await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+        assert_eq!(handler.mails.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_starttls() {
+        use crate::tls::{test::*, Certificate, TlsSettings};
+
+        let handler = Arc::new(TestHandler::default());
+        let mut config = config();
+        config.certificate = Some(
+            CertificateHandle::new(
+                Certificate::from_pem(CHAIN, PRIVATE_KEY).unwrap(),
+                TlsSettings::default(),
+            )
+            .unwrap(),
+        );
+        let mut client = Client::connect(config, Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        client.send(b"EHLO client.example.com\r\n").await;
+        assert!(client.reply_text().await.contains("STARTTLS"));
+        assert_eq!(client.command(b"AUTH EXTERNAL =\r\n").await, 530);
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 220);
+
+        // the greeting is forgotten and the capabilities change
+        let mut client = client.starttls("localhost").await;
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            503
+        );
+        client.send(b"EHLO client.example.com\r\n").await;
+        assert!(!client.reply_text().await.contains("STARTTLS"));
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 503);
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            250
+        );
+        assert_eq!(client.command(b"RCPT TO:<bob@example.com>\r\n").await, 250);
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+        assert_eq!(handler.mails.lock().unwrap().len(), 1);
+        let sessions = handler.sessions.lock().unwrap();
+        assert!(sessions[0].tls.is_some());
+        assert_eq!(
+            sessions[0].greeting,
+            Some(Greeting::Ehlo("client.example.com".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_auth_plain_and_login() {
+        use crate::tls::{test::*, Certificate, TlsSettings};
+
+        let handler = Arc::new(TestHandler::default());
+        let mut config = config();
+        config.auth_required = true;
+        config.certificate = Some(
+            CertificateHandle::new(
+                Certificate::from_pem(CHAIN, PRIVATE_KEY).unwrap(),
+                TlsSettings::default(),
+            )
+            .unwrap(),
+        );
+
+        let mut client = Client::connect(config.clone(), Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"EHLO client.example.com\r\n").await, 250);
+        assert_eq!(
+            client.command(b"AUTH PLAIN AGFsaWNlAHNlY3JldA==\r\n").await,
+            530
+        );
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 220);
+        let mut client = client.starttls("localhost").await;
+        client.send(b"EHLO client.example.com\r\n").await;
+        let capabilities = client.reply_text().await;
+        assert!(capabilities.contains("AUTH PLAIN LOGIN\r\n"));
+        assert_eq!(client.command(b"AUTH CRAM-MD5\r\n").await, 504);
+        assert_eq!(
+            client.command(b"AUTH PLAIN AGFsaWNlAHdyb25n\r\n").await,
+            535
+        );
+        // the authorization identity must be the user itself
+        assert_eq!(
+            client
+                .command(b"AUTH PLAIN Ym9iAGFsaWNlAHNlY3JldA==\r\n")
+                .await,
+            535
+        );
+        // the response needs the three fields
+        assert_eq!(
+            client.command(b"AUTH PLAIN YWxpY2UAc2VjcmV0\r\n").await,
+            501
+        );
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            530
+        );
+        assert_eq!(client.command(b"AUTH PLAIN\r\n").await, 334);
+        assert_eq!(client.command(b"AGFsaWNlAHNlY3JldA==\r\n").await, 235);
+        assert_eq!(client.command(b"AUTH LOGIN\r\n").await, 503);
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            250
+        );
+        assert_eq!(client.command(b"RCPT TO:<bob@example.com>\r\n").await, 250);
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let mut client = Client::connect(config, Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 220);
+        let mut client = client.starttls("localhost").await;
+        assert_eq!(client.command(b"EHLO client.example.com\r\n").await, 250);
+        client.send(b"AUTH LOGIN\r\n").await;
+        assert_eq!(client.reply_text().await, "334 VXNlcm5hbWU6\r\n");
+        client.send(b"YWxpY2U=\r\n").await;
+        assert_eq!(client.reply_text().await, "334 UGFzc3dvcmQ6\r\n");
+        assert_eq!(client.command(b"*\r\n").await, 501);
+        // the username may be the initial response
+        assert_eq!(client.command(b"AUTH LOGIN YWxpY2U=\r\n").await, 334);
+        assert_eq!(client.command(b"c2VjcmV0\r\n").await, 235);
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            250
+        );
+        assert_eq!(client.command(b"RCPT TO:<bob@example.com>\r\n").await, 250);
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let sessions = handler.sessions.lock().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions
+            .iter()
+            .all(|session| session.authenticated.as_deref() == Some("alice")));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_certificate_reload() {
+        use crate::tls::{test::*, Certificate, TlsSettings};
+
+        let handle = CertificateHandle::new(
+            Certificate::from_pem(CHAIN, PRIVATE_KEY).unwrap(),
+            TlsSettings::default(),
+        )
+        .unwrap();
+        let mut config = config();
+        config.certificate = Some(handle.clone());
+
+        let mut old_client = Client::connect(config.clone(), Arc::default());
+        assert_eq!(old_client.reply().await, 220);
+
+        handle
+            .reload(
+                Certificate::from_pem(
+                    include_bytes!("../tests/fixtures/example.org.pem"),
+                    include_bytes!("../tests/fixtures/example.org.key"),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let mut new_client = Client::connect(config, Arc::default());
+        assert_eq!(new_client.reply().await, 220);
+        assert_eq!(new_client.command(b"STARTTLS\r\n").await, 220);
+        let mut new_client = new_client.starttls("mail.example.org").await;
+        assert_eq!(new_client.command(b"QUIT\r\n").await, 221);
+        new_client.closed().await;
+
+        assert_eq!(old_client.command(b"STARTTLS\r\n").await, 220);
+        let mut old_client = old_client.starttls("localhost").await;
+        assert_eq!(old_client.command(b"QUIT\r\n").await, 221);
+        old_client.closed().await;
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_sni() {
+        use crate::tls::{test::*, Certificate, TlsSettings};
+
+        let handle = CertificateHandle::new(
+            Certificate::from_pem(CHAIN, PRIVATE_KEY).unwrap(),
+            TlsSettings::default(),
+        )
+        .unwrap();
+        handle
+            .reload_for(
+                "mail.example.org",
+                Certificate::from_pem(
+                    include_bytes!("../tests/fixtures/example.org.pem"),
+                    include_bytes!("../tests/fixtures/example.org.key"),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let mut config = config();
+        config.certificate = Some(handle);
+
+        // the domain of the server is kept by default
+        let mut client = Client::connect(config.clone(), Arc::default());
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 220);
+        let mut client = client.starttls("mail.example.org").await;
+        client.send(b"EHLO client.example.com\r\n").await;
+        assert!(client
+            .reply_text()
+            .await
+            .starts_with("250-example.com greets"));
+
+        // or it follows the certificate
+        config.sni_domain = true;
+        let mut client = Client::connect(config.clone(), Arc::default());
+        assert!(client.reply_text().await.starts_with("220 example.com "));
+        client.send(b"EHLO client.example.com\r\n").await;
+        assert!(client
+            .reply_text()
+            .await
+            .starts_with("250-example.com greets"));
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 220);
+        let mut client = client.starttls("mail.example.org").await;
+        client.send(b"EHLO client.example.com\r\n").await;
+        assert!(client
+            .reply_text()
+            .await
+            .starts_with("250-mail.example.org greets"));
+
+        // clients asking for another hostname get the default certificate
+        let mut client = Client::connect(config.clone(), Arc::default());
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 220);
+        let mut client = client.starttls("localhost").await;
+        client.send(b"EHLO client.example.com\r\n").await;
+        assert!(client
+            .reply_text()
+            .await
+            .starts_with("250-example.com greets"));
+
+        // with implicit TLS, the greeting follows the certificate too
+        config.implicit_tls = true;
+        config.tls_required = true;
+        let client = Client::connect(config, Arc::default());
+        let mut client = client.starttls("mail.example.org").await;
+        assert!(client
+            .reply_text()
+            .await
+            .starts_with("220 mail.example.org "));
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_auth_external() {
+        use crate::tls::{test::*, Certificate, TlsSettings};
+
+        let handler = Arc::new(TestHandler::default());
+        let mut config = config();
+        config.auth_required = true;
+        let settings = TlsSettings::default().client_ca(CA);
+        config.certificate = Some(
+            CertificateHandle::new(Certificate::from_pem(CHAIN, PRIVATE_KEY).unwrap(), settings)
+                .unwrap(),
+        );
+
+        // clients without a certificate can use TLS, but not AUTH EXTERNAL
+        let mut client = Client::connect(config.clone(), Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 220);
+        let mut client = client.starttls("localhost").await;
+        client.send(b"EHLO client.example.com\r\n").await;
+        assert!(!client.reply_text().await.contains("EXTERNAL"));
+        assert_eq!(client.command(b"AUTH EXTERNAL =\r\n").await, 535);
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            530
+        );
+
+        let mut client = Client::connect(config, Arc::clone(&handler));
+        assert_eq!(client.reply().await, 220);
+        assert_eq!(client.command(b"STARTTLS\r\n").await, 220);
+        let mut client = client.starttls_with_certificate("localhost").await;
+        client.send(b"EHLO client.example.com\r\n").await;
+        assert!(client
+            .reply_text()
+            .await
+            .contains("AUTH PLAIN LOGIN EXTERNAL"));
+        assert_eq!(client.command(b"AUTH EXTERNAL b3RoZXI=\r\n").await, 535);
+        assert_eq!(client.command(b"AUTH EXTERNAL\r\n").await, 334);
+        assert_eq!(client.command(b"*\r\n").await, 501);
+        assert_eq!(client.command(b"AUTH EXTERNAL\r\n").await, 334);
+        assert_eq!(client.command(b"not base64\r\n").await, 501);
+        assert_eq!(client.command(b"AUTH EXTERNAL\r\n").await, 334);
+        assert_eq!(client.command(b"\r\n").await, 235);
+        assert_eq!(client.command(b"AUTH EXTERNAL =\r\n").await, 503);
+
+        assert_eq!(
+            client.command(b"MAIL FROM:<alice@example.com>\r\n").await,
+            250
+        );
+        assert_eq!(client.command(b"RCPT TO:<bob@example.com>\r\n").await, 250);
+        assert_eq!(client.command(b"DATA\r\n").await, 354);
+        assert_eq!(client.command(EMAIL).await, 250);
+        assert_eq!(client.command(b"QUIT\r\n").await, 221);
+        client.closed().await;
+
+        let sessions = handler.sessions.lock().unwrap();
+        assert_eq!(sessions[0].authenticated.as_deref(), Some("relay"));
+        let certificate = sessions[0]
+            .tls
+            .as_ref()
+            .unwrap()
+            .client_certificate
+            .as_ref()
+            .unwrap();
+        assert_eq!(
+            certificate.fingerprint,
+            "94:1A:F9:D1:79:A1:D8:4F:76:FB:4F:1A:96:BA:C0:99:5E:1D:A3:FA:E3:DD:DB:EC:C5:16:A8:2C:30:E9:DB:91"
+        );
+    }
 }
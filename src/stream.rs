@@ -1,21 +1,31 @@
-use crate::replies::Reply;
-use bytes::BufMut;
-use native_tls::Error as TlsError;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream as UnencryptedTcpStream;
-use tokio_native_tls::TlsAcceptor;
-use tokio_native_tls::TlsStream as EncryptedTcpStream;
+use crate::{
+    error::SessionError,
+    replies::Reply,
+    session::TlsInfo,
+    tls::{Certificates, TlsStream},
+};
+use bytes::{Buf, BufMut, BytesMut};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 
-pub enum TcpStream {
-    Unencrypted(UnencryptedTcpStream),
-    Encrypted(EncryptedTcpStream<UnencryptedTcpStream>),
+/// A client connection, over TCP or a Unix domain socket, which may have been upgraded to TLS.
+pub enum Stream<S> {
+    Unencrypted(S),
+    Encrypted(Box<TlsStream<Rewind<S>>>),
 }
 
-impl TcpStream {
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
     pub async fn send_reply(&mut self, reply: Reply) -> std::result::Result<(), std::io::Error> {
         match self {
-            TcpStream::Unencrypted(s) => s.write_all(reply.to_string().as_bytes()).await,
-            TcpStream::Encrypted(s) => s.write_all(reply.to_string().as_bytes()).await,
+            Stream::Unencrypted(s) => s.write_all(reply.to_string().as_bytes()).await,
+            Stream::Encrypted(s) => {
+                // TLS libraries may buffer the records until flushed
+                s.write_all(reply.to_string().as_bytes()).await?;
+                s.flush().await
+            }
         }
     }
 
@@ -28,29 +38,261 @@ impl TcpStream {
         B: BufMut,
     {
         match self {
-            TcpStream::Unencrypted(s) => s.read_buf(buf).await,
-            TcpStream::Encrypted(s) => s.read_buf(buf).await,
+            Stream::Unencrypted(s) => s.read_buf(buf).await,
+            Stream::Encrypted(s) => s.read_buf(buf).await,
         }
     }
 
     pub async fn shutdown(&mut self) -> std::result::Result<(), std::io::Error> {
         match self {
-            TcpStream::Unencrypted(s) => s.shutdown().await,
-            TcpStream::Encrypted(s) => s.shutdown().await,
+            Stream::Unencrypted(s) => s.shutdown().await,
+            Stream::Encrypted(s) => s.shutdown().await,
         }
     }
 
-    pub async fn accept(self, tls_acceptor: &TlsAcceptor) -> Result<TcpStream, TlsError> {
+    /// Upgrade to TLS, with the certificate matching the hostname the client asked for.
+    /// Returns that hostname if it has its own certificate.
+    pub async fn accept(
+        self,
+        certificates: &Certificates,
+    ) -> Result<(Stream<S>, Option<String>), SessionError> {
+        let mut socket = match self {
+            Stream::Unencrypted(socket) => socket,
+            Stream::Encrypted(s) => return Ok((Stream::Encrypted(s), None)),
+        };
+
+        // native-tls cannot select a certificate during the handshake, so with both backends the
+        // ClientHello is read beforehand and replayed to the TLS library
+        let mut client_hello = BytesMut::new();
+        let mut server_name = None;
+        if certificates.use_sni() {
+            server_name = loop {
+                if let Ok(server_name) = crate::tls::server_name(&client_hello) {
+                    break server_name;
+                }
+                if socket.read_buf(&mut client_hello).await? == 0 {
+                    return Err(SessionError::ConnectionClosed);
+                }
+            };
+        }
+
+        let (tls_acceptor, hostname) = certificates.select(server_name.as_deref());
+        let stream = tls_acceptor
+            .accept(Rewind {
+                prefix: client_hello,
+                inner: socket,
+            })
+            .await?;
+        Ok((Stream::Encrypted(Box::new(stream)), hostname))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Stream::Encrypted(_))
+    }
+
+    /// The TLS parameters negotiated with the client, if the stream is encrypted.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
         match self {
-            TcpStream::Unencrypted(s) => match tls_acceptor.accept(s).await {
-                Ok(s) => Ok(TcpStream::Encrypted(s)),
-                Err(e) => Err(e),
-            },
-            TcpStream::Encrypted(s) => Ok(TcpStream::Encrypted(s)),
+            Stream::Unencrypted(_) => None,
+            Stream::Encrypted(s) => Some(crate::tls::info(s.as_ref())),
+        }
+    }
+}
+
+/// A [Stream] with a read buffer, split into lines.
+///
+/// Commands are parsed from lines owned by the caller, so they can borrow from them
+/// while the connection keeps reading and writing.
+pub struct Connection<S> {
+    stream: Stream<S>,
+    buffer: BytesMut,
+    discarding: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: Stream<S>) -> Connection<S> {
+        Connection {
+            stream,
+            buffer: BytesMut::with_capacity(1024),
+            discarding: false,
+        }
+    }
+
+    pub async fn send_reply(&mut self, reply: Reply) -> std::result::Result<(), std::io::Error> {
+        self.stream.send_reply(reply).await
+    }
+
+    pub async fn shutdown(&mut self) -> std::result::Result<(), std::io::Error> {
+        self.stream.shutdown().await
+    }
+
+    /// Upgrade to TLS. Anything the client sent before the handshake is discarded (RFC 3207 section 4.2).
+    /// Returns the hostname the client asked for, if it has its own certificate.
+    pub async fn accept(
+        self,
+        certificates: &Certificates,
+    ) -> Result<(Connection<S>, Option<String>), SessionError> {
+        if !self.buffer.is_empty() {
+            warn!(
+                "Discarding {} bytes received before the TLS handshake",
+                self.buffer.len()
+            );
         }
+        let (stream, hostname) = self.stream.accept(certificates).await?;
+        Ok((Connection::new(stream), hostname))
     }
 
     pub fn is_encrypted(&self) -> bool {
-        matches!(self, TcpStream::Encrypted(_))
+        self.stream.is_encrypted()
+    }
+
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.stream.tls_info()
+    }
+
+    /// Read the next line, including its CRLF. Returns `None` if the client closed the connection between two lines.
+    /// A line longer than `max_length` is skipped and reported as [SessionError::LineTooLong].
+    pub async fn read_line(&mut self, max_length: usize) -> Result<Option<BytesMut>, SessionError> {
+        loop {
+            match take_line(&mut self.buffer, max_length, &mut self.discarding) {
+                Line::Complete(line) => return Ok(Some(line)),
+                Line::TooLong => return Err(SessionError::LineTooLong),
+                Line::Incomplete => (),
+            }
+
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() && !self.discarding {
+                    return Ok(None);
+                }
+                return Err(SessionError::ConnectionClosed);
+            }
+        }
+    }
+}
+
+/// A stream giving back some bytes that were already read from it, before reading more.
+pub struct Rewind<S> {
+    prefix: BytesMut,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = std::cmp::min(self.prefix.len(), buf.remaining());
+            buf.put_slice(&self.prefix.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Line {
+    Complete(BytesMut),
+    TooLong,
+    Incomplete,
+}
+
+/// Split the first line off the buffer.
+/// While `discarding`, the bytes of a line that was too long are dropped until its CRLF.
+fn take_line(buffer: &mut BytesMut, max_length: usize, discarding: &mut bool) -> Line {
+    match buffer.windows(2).position(|w| w == b"\r\n") {
+        Some(position) => {
+            let line = buffer.split_to(position + 2);
+            if *discarding || line.len() > max_length {
+                *discarding = false;
+                Line::TooLong
+            } else {
+                Line::Complete(line)
+            }
+        }
+        None if *discarding || buffer.len() > max_length => {
+            *discarding = true;
+            // keep a trailing CR which may be followed by the LF ending the line
+            let keep = if buffer.ends_with(b"\r") { 1 } else { 0 };
+            buffer.advance(buffer.len() - keep);
+            Line::Incomplete
+        }
+        None => Line::Incomplete,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(chunks: &[&[u8]], max_length: usize) -> Vec<Line> {
+        let mut buffer = BytesMut::new();
+        let mut discarding = false;
+        let mut lines = Vec::new();
+        for chunk in chunks {
+            buffer.extend_from_slice(chunk);
+            loop {
+                match take_line(&mut buffer, max_length, &mut discarding) {
+                    Line::Incomplete => break,
+                    line => lines.push(line),
+                }
+            }
+        }
+        lines
+    }
+
+    fn complete(line: &[u8]) -> Line {
+        Line::Complete(BytesMut::from(line))
+    }
+
+    #[test]
+    fn test_take_line() {
+        assert_eq!(lines(&[b"NOOP\r\n"], 100), vec![complete(b"NOOP\r\n")]);
+        assert_eq!(
+            lines(&[b"NO", b"OP\r", b"\n"], 100),
+            vec![complete(b"NOOP\r\n")]
+        );
+        assert_eq!(
+            lines(&[b"EHLO a\r\nMAIL FROM:<>\r\nRC"], 100),
+            vec![complete(b"EHLO a\r\n"), complete(b"MAIL FROM:<>\r\n")]
+        );
+        assert_eq!(lines(&[b"\n\r\r\n"], 100), vec![complete(b"\n\r\r\n")]);
+        assert_eq!(lines(&[b"NOOP"], 100), vec![]);
+    }
+
+    #[test]
+    fn test_take_long_line() {
+        assert_eq!(
+            lines(&[b"0123456789\r\n"], 12),
+            vec![complete(b"0123456789\r\n")]
+        );
+        assert_eq!(lines(&[b"0123456789\r\n"], 11), vec![Line::TooLong]);
+        assert_eq!(
+            lines(&[b"0123456789", b"0123456789\r", b"\nNOOP\r\n"], 8),
+            vec![Line::TooLong, complete(b"NOOP\r\n")]
+        );
+        assert_eq!(
+            lines(&[&[b'a'; 1000], &[b'a'; 1000], b"\r\nNOOP\r\n"], 100),
+            vec![Line::TooLong, complete(b"NOOP\r\n")]
+        );
     }
 }
@@ -0,0 +1,643 @@
+use crate::error::TlsError;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod native;
+#[cfg(feature = "rustls")]
+mod rustls;
+
+#[cfg(feature = "rustls")]
+use self::rustls as backend;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+use native as backend;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("either the native-tls or the rustls feature must be enabled");
+
+pub(crate) use backend::{info, Acceptor, TlsStream};
+
+/// The error type of the TLS library selected with the cargo features:
+/// `native_tls::Error`, or `rustls::TLSError` with the rustls feature.
+pub use backend::Error as LibraryError;
+
+/// A certificate chain and its private key, ready to be served to clients.
+///
+/// ```no_run
+/// # use smtp_server::{tls::Certificate, error::TlsError};
+/// # fn example() -> Result<(), TlsError> {
+/// let certificate = Certificate::from_pem_files(
+///     "/etc/letsencrypt/live/mail.example.com/fullchain.pem",
+///     "/etc/letsencrypt/live/mail.example.com/privkey.pem",
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Certificate {
+    identity: backend::Identity,
+}
+
+impl Certificate {
+    /// Load a PKCS#12 archive (`.pfx` or `.p12`). Only supported with the native-tls feature.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Certificate, TlsError> {
+        Certificate::new(backend::Identity::from_pkcs12(der, password)?)
+    }
+
+    /// Load a PEM certificate chain, starting with the certificate of the server, and its PEM private key.
+    pub fn from_pem(chain: &[u8], private_key: &[u8]) -> Result<Certificate, TlsError> {
+        Certificate::new(backend::Identity::from_pem(chain, private_key)?)
+    }
+
+    /// Load a PKCS#12 archive from a file.
+    pub fn from_pkcs12_file<P: AsRef<Path>>(
+        path: P,
+        password: &str,
+    ) -> Result<Certificate, TlsError> {
+        Certificate::from_pkcs12(&read(path.as_ref())?, password)
+    }
+
+    /// Load a PEM certificate chain and its private key from files, as written by ACME clients
+    /// (`fullchain.pem` and `privkey.pem` for certbot).
+    pub fn from_pem_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        chain: P,
+        private_key: Q,
+    ) -> Result<Certificate, TlsError> {
+        Certificate::from_pem(&read(chain.as_ref())?, &read(private_key.as_ref())?)
+    }
+
+    /// Make sure the TLS library accepts the certificate now, rather than when a client connects.
+    fn new(identity: backend::Identity) -> Result<Certificate, TlsError> {
+        Acceptor::new(&identity, &TlsSettings::default())?;
+        Ok(Certificate { identity })
+    }
+}
+
+impl std::fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Certificate").finish()
+    }
+}
+
+/// A version of the TLS protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+/// Restrictions on the TLS protocol versions and cipher suites offered to clients.
+/// By default, everything the TLS library supports is allowed.
+///
+/// ```
+/// # use smtp_server::tls::{TlsSettings, TlsVersion};
+/// let settings = TlsSettings::default()
+///     .min_version(TlsVersion::Tls12)
+///     .cipher_suites(&["TLS13_AES_256_GCM_SHA384", "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsSettings {
+    pub(crate) min_version: Option<TlsVersion>,
+    pub(crate) max_version: Option<TlsVersion>,
+    pub(crate) cipher_suites: Option<Vec<String>>,
+    pub(crate) client_ca: Option<Vec<u8>>,
+}
+
+impl TlsSettings {
+    pub fn min_version(mut self, version: TlsVersion) -> Self {
+        self.min_version = Some(version);
+        self
+    }
+
+    pub fn max_version(mut self, version: TlsVersion) -> Self {
+        self.max_version = Some(version);
+        self
+    }
+
+    /// Only allow these cipher suites, named like in rustls (`TLS13_AES_128_GCM_SHA256`, `TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256`...).
+    /// Only supported with the rustls feature.
+    pub fn cipher_suites(mut self, cipher_suites: &[&str]) -> Self {
+        self.cipher_suites = Some(cipher_suites.iter().map(|name| name.to_string()).collect());
+        self
+    }
+
+    /// Ask the clients for a certificate issued by one of these PEM CA certificates, for mutual TLS between servers.
+    /// Clients without a certificate are still accepted, but they cannot use AUTH EXTERNAL.
+    /// Only supported with the rustls feature.
+    pub fn client_ca(mut self, pem: &[u8]) -> Self {
+        self.client_ca = Some(pem.to_vec());
+        self
+    }
+
+    /// Make sure the TLS library can apply these settings.
+    pub(crate) fn validate(&self) -> Result<(), TlsError> {
+        if let (Some(min), Some(max)) = (self.min_version, self.max_version) {
+            if min > max {
+                return Err(TlsError::InvalidSettings(format!(
+                    "the minimum version {:?} is above the maximum version {:?}",
+                    min, max
+                )));
+            }
+        }
+        backend::validate(self)
+    }
+}
+
+/// A handle to replace the certificates of a running server, for example after a renewal.
+/// New connections use the new certificates, sessions already in progress keep the old ones.
+///
+/// ```no_run
+/// # use smtp_server::{tls::Certificate, EventHandler, SmtpServer};
+/// # fn example(handler: impl EventHandler + 'static) -> Result<(), Box<dyn std::error::Error>> {
+/// let mut server = SmtpServer::new(handler, "mail.example.com");
+/// server.tls_pem("fullchain.pem", "privkey.pem")?;
+/// let handle = server.certificate_handle().unwrap();
+///
+/// // later, from another task
+/// handle.reload(Certificate::from_pem_files("fullchain.pem", "privkey.pem")?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CertificateHandle {
+    state: Arc<RwLock<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    settings: TlsSettings,
+    certificates: Arc<Certificates>,
+}
+
+impl CertificateHandle {
+    pub(crate) fn new(
+        certificate: Certificate,
+        settings: TlsSettings,
+    ) -> Result<CertificateHandle, TlsError> {
+        let certificates = Certificates {
+            default: (
+                Acceptor::new(&certificate.identity, &settings)?,
+                certificate,
+            ),
+            hostnames: HashMap::new(),
+        };
+
+        Ok(CertificateHandle {
+            state: Arc::new(RwLock::new(State {
+                settings,
+                certificates: Arc::new(certificates),
+            })),
+        })
+    }
+
+    /// Serve this certificate to the next clients, unless they asked for a hostname with its own certificate.
+    pub fn reload(&self, certificate: Certificate) -> Result<(), TlsError> {
+        self.update(|certificates, settings| {
+            certificates.default = (Acceptor::new(&certificate.identity, settings)?, certificate);
+            Ok(())
+        })
+    }
+
+    /// Serve this certificate to the next clients asking for `hostname` with SNI.
+    /// The hostname can be a wildcard like `*.example.com`.
+    pub fn reload_for(&self, hostname: &str, certificate: Certificate) -> Result<(), TlsError> {
+        self.update(|certificates, settings| {
+            let acceptor = Acceptor::new(&certificate.identity, settings)?;
+            certificates
+                .hostnames
+                .insert(normalize(hostname), (acceptor, certificate));
+            Ok(())
+        })
+    }
+
+    /// Apply new settings to all the certificates.
+    pub(crate) fn set_settings(&self, settings: TlsSettings) -> Result<(), TlsError> {
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut certificates = Certificates::clone(&state.certificates);
+        for (acceptor, certificate) in
+            std::iter::once(&mut certificates.default).chain(certificates.hostnames.values_mut())
+        {
+            *acceptor = Acceptor::new(&certificate.identity, &settings)?;
+        }
+        state.settings = settings;
+        state.certificates = Arc::new(certificates);
+        Ok(())
+    }
+
+    /// The certificates to use for a new connection.
+    pub(crate) fn certificates(&self) -> Arc<Certificates> {
+        let state = self
+            .state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Arc::clone(&state.certificates)
+    }
+
+    fn update<F: FnOnce(&mut Certificates, &TlsSettings) -> Result<(), TlsError>>(
+        &self,
+        f: F,
+    ) -> Result<(), TlsError> {
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut certificates = Certificates::clone(&state.certificates);
+        f(&mut certificates, &state.settings)?;
+        state.certificates = Arc::new(certificates);
+        Ok(())
+    }
+}
+
+/// The certificates of the server, selected with the hostname the client asked for.
+#[derive(Debug, Clone)]
+pub(crate) struct Certificates {
+    default: (Acceptor, Certificate),
+    hostnames: HashMap<String, (Acceptor, Certificate)>,
+}
+
+impl Certificates {
+    /// Whether the certificate depends on the hostname requested by the client.
+    pub(crate) fn use_sni(&self) -> bool {
+        !self.hostnames.is_empty()
+    }
+
+    /// The certificate for the hostname requested by the client, and that hostname if it has its own certificate.
+    pub(crate) fn select(&self, server_name: Option<&str>) -> (&Acceptor, Option<String>) {
+        if let Some(server_name) = server_name {
+            let server_name = normalize(server_name);
+            let wildcard = server_name
+                .split_once('.')
+                .map(|(_, parent)| format!("*.{}", parent));
+            let certificate = self
+                .hostnames
+                .get(&server_name)
+                .or_else(|| wildcard.and_then(|wildcard| self.hostnames.get(&wildcard)));
+            if let Some((acceptor, _)) = certificate {
+                return (acceptor, Some(server_name));
+            }
+        }
+        (&self.default.0, None)
+    }
+}
+
+fn normalize(hostname: &str) -> String {
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The ClientHello message was not entirely received yet.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Incomplete;
+
+/// Find the hostname requested by the client in the server name extension of its ClientHello (RFC 6066 section 3).
+///
+/// Only the first record is read, since it can hold 16 KiB and a ClientHello is much smaller in practice.
+/// Anything that does not look like a ClientHello gives `None`, and the handshake will fail on its own.
+pub(crate) fn server_name(data: &[u8]) -> Result<Option<String>, Incomplete> {
+    const HANDSHAKE: u8 = 22;
+    const CLIENT_HELLO: u8 = 1;
+    const SERVER_NAME: u16 = 0;
+    const HOST_NAME: u8 = 0;
+
+    if data.len() < 5 {
+        return Err(Incomplete);
+    }
+    if data[0] != HANDSHAKE {
+        return Ok(None);
+    }
+    let record_length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    let record = match data.get(5..5 + record_length) {
+        Some(record) => record,
+        None => return Err(Incomplete),
+    };
+
+    let mut reader = Reader(record);
+    let server_name = (|| {
+        if reader.u8()? != CLIENT_HELLO {
+            return None;
+        }
+        reader.skip(3 + 2 + 32)?; // length, version and random
+        let session_id_length = reader.u8()? as usize;
+        reader.skip(session_id_length)?;
+        let cipher_suites_length = reader.u16()? as usize;
+        reader.skip(cipher_suites_length)?;
+        let compression_methods_length = reader.u8()? as usize;
+        reader.skip(compression_methods_length)?;
+
+        let extensions_length = reader.u16()? as usize;
+        let mut extensions = Reader(reader.take(extensions_length)?);
+        while !extensions.0.is_empty() {
+            let extension_type = extensions.u16()?;
+            let extension_length = extensions.u16()? as usize;
+            let mut extension = Reader(extensions.take(extension_length)?);
+            if extension_type != SERVER_NAME {
+                continue;
+            }
+
+            let list_length = extension.u16()? as usize;
+            let mut list = Reader(extension.take(list_length)?);
+            while !list.0.is_empty() {
+                let name_type = list.u8()?;
+                let name_length = list.u16()? as usize;
+                let name = list.take(name_length)?;
+                if name_type == HOST_NAME {
+                    return std::str::from_utf8(name).ok().map(|name| name.to_string());
+                }
+            }
+        }
+        None
+    })();
+
+    Ok(server_name)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        self.take(length).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|error| TlsError::Io {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::session::TlsInfo;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[cfg(feature = "rustls")]
+    pub(crate) use super::backend::test::connect_with_certificate;
+    pub(crate) use super::backend::test::{connect, ClientStream};
+
+    pub(crate) const CA: &[u8] = include_bytes!("../tests/fixtures/ca.pem");
+    pub(crate) const CHAIN: &[u8] = include_bytes!("../tests/fixtures/localhost.pem");
+    pub(crate) const PRIVATE_KEY: &[u8] = include_bytes!("../tests/fixtures/localhost.key");
+    pub(crate) const OTHER_CHAIN: &[u8] = include_bytes!("../tests/fixtures/example.org.pem");
+    pub(crate) const OTHER_PRIVATE_KEY: &[u8] = include_bytes!("../tests/fixtures/example.org.key");
+    #[cfg(feature = "rustls")]
+    pub(crate) const CLIENT_CHAIN: &[u8] = include_bytes!("../tests/fixtures/client.pem");
+    #[cfg(feature = "rustls")]
+    pub(crate) const CLIENT_PRIVATE_KEY: &[u8] = include_bytes!("../tests/fixtures/client.key");
+    #[cfg(not(feature = "rustls"))]
+    const PKCS12: &[u8] = include_bytes!("../tests/fixtures/localhost.pfx");
+
+    /// Make sure a client can complete a handshake with this certificate, and return what was negotiated.
+    async fn handshake(certificate: Certificate, settings: TlsSettings) -> TlsInfo {
+        let acceptor = Acceptor::new(&certificate.identity, &settings).unwrap();
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            stream.write_all(b"220 ready\r\n").await.unwrap();
+            stream.shutdown().await.unwrap();
+            info(&stream)
+        });
+        let mut client = connect("localhost", client, true).await;
+        let mut greeting = String::new();
+        client.read_to_string(&mut greeting).await.unwrap();
+        assert_eq!(greeting, "220 ready\r\n");
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_load_certificates() {
+        handshake(
+            Certificate::from_pem(CHAIN, PRIVATE_KEY).unwrap(),
+            TlsSettings::default(),
+        )
+        .await;
+        handshake(
+            Certificate::from_pem_files(
+                "tests/fixtures/localhost.pem",
+                "tests/fixtures/localhost.key",
+            )
+            .unwrap(),
+            TlsSettings::default(),
+        )
+        .await;
+        #[cfg(not(feature = "rustls"))]
+        {
+            handshake(
+                Certificate::from_pkcs12(PKCS12, "password").unwrap(),
+                TlsSettings::default(),
+            )
+            .await;
+            handshake(
+                Certificate::from_pkcs12_file("tests/fixtures/localhost.pfx", "password").unwrap(),
+                TlsSettings::default(),
+            )
+            .await;
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_invalid_certificates() {
+        #[cfg(not(feature = "rustls"))]
+        {
+            assert!(matches!(
+                Certificate::from_pkcs12(PKCS12, "wrong password"),
+                Err(TlsError::InvalidIdentity(_))
+            ));
+            assert!(matches!(
+                Certificate::from_pkcs12(CHAIN, ""),
+                Err(TlsError::InvalidIdentity(_))
+            ));
+        }
+        #[cfg(feature = "rustls")]
+        assert!(matches!(
+            Certificate::from_pkcs12(&[], ""),
+            Err(TlsError::Unsupported(_))
+        ));
+        assert!(matches!(
+            Certificate::from_pem(CHAIN, OTHER_PRIVATE_KEY),
+            Err(TlsError::KeyMismatch)
+        ));
+        assert!(matches!(
+            Certificate::from_pem(CHAIN, include_bytes!("../tests/fixtures/other.key")),
+            Err(TlsError::KeyMismatch)
+        ));
+        assert!(matches!(
+            Certificate::from_pem(PRIVATE_KEY, PRIVATE_KEY),
+            Err(TlsError::InvalidPem(_))
+        ));
+        assert!(matches!(
+            Certificate::from_pem(CHAIN, CHAIN),
+            Err(TlsError::InvalidPem(_))
+        ));
+        assert!(matches!(
+            Certificate::from_pem_files(
+                "tests/fixtures/missing.pem",
+                "tests/fixtures/localhost.key"
+            ),
+            Err(TlsError::Io { .. })
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_settings() {
+        assert!(matches!(
+            TlsSettings::default()
+                .min_version(TlsVersion::Tls13)
+                .max_version(TlsVersion::Tls12)
+                .validate(),
+            Err(TlsError::InvalidSettings(_))
+        ));
+
+        let certificate = Certificate::from_pem(CHAIN, PRIVATE_KEY).unwrap();
+        let settings = TlsSettings::default().max_version(TlsVersion::Tls12);
+        #[cfg(not(feature = "rustls"))]
+        {
+            assert_eq!(
+                handshake(certificate.clone(), settings).await,
+                TlsInfo::default()
+            );
+            assert!(matches!(
+                TlsSettings::default()
+                    .cipher_suites(&["TLS13_AES_128_GCM_SHA256"])
+                    .validate(),
+                Err(TlsError::Unsupported(_))
+            ));
+            assert!(matches!(
+                TlsSettings::default()
+                    .min_version(TlsVersion::Tls13)
+                    .validate(),
+                Err(TlsError::Unsupported(_))
+            ));
+            assert!(matches!(
+                TlsSettings::default().client_ca(CA).validate(),
+                Err(TlsError::Unsupported(_))
+            ));
+        }
+        #[cfg(feature = "rustls")]
+        {
+            let tls = handshake(certificate.clone(), settings).await;
+            assert_eq!(tls.protocol.as_deref(), Some("TLSv1.2"));
+            let tls = handshake(certificate.clone(), TlsSettings::default()).await;
+            assert_eq!(tls.protocol.as_deref(), Some("TLSv1.3"));
+
+            let settings =
+                TlsSettings::default().cipher_suites(&["TLS13_CHACHA20_POLY1305_SHA256"]);
+            let tls = handshake(certificate.clone(), settings).await;
+            assert_eq!(
+                tls.cipher.as_deref(),
+                Some("TLS13_CHACHA20_POLY1305_SHA256")
+            );
+
+            assert!(matches!(
+                TlsSettings::default()
+                    .cipher_suites(&["TLS_RSA_WITH_RC4_128_MD5"])
+                    .validate(),
+                Err(TlsError::InvalidSettings(_))
+            ));
+            assert!(matches!(
+                TlsSettings::default()
+                    .min_version(TlsVersion::Tls13)
+                    .cipher_suites(&["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"])
+                    .validate(),
+                Err(TlsError::InvalidSettings(_))
+            ));
+            assert!(TlsSettings::default().client_ca(CA).validate().is_ok());
+            assert!(matches!(
+                TlsSettings::default().client_ca(PRIVATE_KEY).validate(),
+                Err(TlsError::InvalidSettings(_))
+            ));
+        }
+    }
+
+    /// The first bytes sent by a client connecting to `domain`.
+    async fn client_hello(domain: &str, sni: bool) -> Vec<u8> {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let domain = domain.to_string();
+        tokio::spawn(async move { connect(&domain, client, sni).await });
+        let mut client_hello = Vec::new();
+        while server_name(&client_hello) == Err(Incomplete) {
+            assert!(server.read_buf(&mut client_hello).await.unwrap() > 0);
+        }
+        client_hello
+    }
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_server_name() {
+        let data = client_hello("mail.example.org", true).await;
+        assert_eq!(server_name(&data), Ok(Some("mail.example.org".to_string())));
+        for length in 0..data.len() {
+            assert_eq!(server_name(&data[..length]), Err(Incomplete));
+        }
+        assert_eq!(
+            server_name(&client_hello("mail.example.org", false).await),
+            Ok(None)
+        );
+
+        assert_eq!(server_name(b"EHLO client.example.com\r\n"), Ok(None));
+        assert_eq!(server_name(&[22, 3, 1, 0, 4, 1, 0, 0, 0]), Ok(None));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_select_certificate() {
+        let handle = CertificateHandle::new(
+            Certificate::from_pem(CHAIN, PRIVATE_KEY).unwrap(),
+            TlsSettings::default(),
+        )
+        .unwrap();
+        assert!(!handle.certificates().use_sni());
+        assert_eq!(
+            handle.certificates().select(Some("mail.example.org")).1,
+            None
+        );
+
+        let certificate = Certificate::from_pem(OTHER_CHAIN, OTHER_PRIVATE_KEY).unwrap();
+        handle
+            .reload_for("Mail.Example.Org.", certificate.clone())
+            .unwrap();
+        handle.reload_for("*.example.net", certificate).unwrap();
+        let certificates = handle.certificates();
+        assert!(certificates.use_sni());
+        assert_eq!(
+            certificates.select(Some("mail.example.org")).1,
+            Some("mail.example.org".to_string())
+        );
+        assert_eq!(
+            certificates.select(Some("MAIL.example.org.")).1,
+            Some("mail.example.org".to_string())
+        );
+        assert_eq!(
+            certificates.select(Some("mx.example.net")).1,
+            Some("mx.example.net".to_string())
+        );
+        assert_eq!(certificates.select(Some("example.net")).1, None);
+        assert_eq!(certificates.select(Some("a.mx.example.net")).1, None);
+        assert_eq!(certificates.select(Some("example.org")).1, None);
+        assert_eq!(certificates.select(None).1, None);
+    }
+}
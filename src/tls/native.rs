@@ -0,0 +1,134 @@
+use super::{TlsSettings, TlsVersion};
+use crate::{
+    error::{SessionError, TlsError},
+    session::TlsInfo,
+};
+use native_tls::Protocol;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub use native_tls::Error;
+pub(crate) use tokio_native_tls::TlsStream;
+
+#[derive(Clone)]
+pub(crate) struct Identity(native_tls::Identity);
+
+impl Identity {
+    pub(crate) fn from_pkcs12(der: &[u8], password: &str) -> Result<Identity, TlsError> {
+        Ok(Identity(
+            native_tls::Identity::from_pkcs12(der, password).map_err(TlsError::InvalidIdentity)?,
+        ))
+    }
+
+    pub(crate) fn from_pem(chain: &[u8], private_key: &[u8]) -> Result<Identity, TlsError> {
+        use openssl::{pkcs12::Pkcs12, pkey::PKey, stack::Stack, x509::X509};
+
+        let mut chain = X509::stack_from_pem(chain)
+            .map_err(|e| TlsError::InvalidPem(e.to_string()))?
+            .into_iter();
+        let certificate = chain
+            .next()
+            .ok_or_else(|| TlsError::InvalidPem("no certificate found".to_string()))?;
+        let private_key = PKey::private_key_from_pem(private_key)
+            .map_err(|e| TlsError::InvalidPem(e.to_string()))?;
+        let public_key = certificate
+            .public_key()
+            .map_err(|e| TlsError::InvalidPem(e.to_string()))?;
+        if !public_key.public_eq(&private_key) {
+            return Err(TlsError::KeyMismatch);
+        }
+
+        // native-tls only loads identities from PKCS#12 archives
+        let mut intermediates = Stack::new().map_err(|e| TlsError::InvalidPem(e.to_string()))?;
+        for intermediate in chain {
+            intermediates
+                .push(intermediate)
+                .map_err(|e| TlsError::InvalidPem(e.to_string()))?;
+        }
+        let archive = Pkcs12::builder()
+            .pkey(&private_key)
+            .cert(&certificate)
+            .ca(intermediates)
+            .build2("")
+            .and_then(|archive| archive.to_der())
+            .map_err(|e| TlsError::InvalidPem(e.to_string()))?;
+
+        Identity::from_pkcs12(&archive, "")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Acceptor(tokio_native_tls::TlsAcceptor);
+
+impl Acceptor {
+    pub(crate) fn new(identity: &Identity, settings: &TlsSettings) -> Result<Acceptor, TlsError> {
+        validate(settings)?;
+        // native-tls does not know TLS 1.3, which is enabled when there is no maximum
+        let acceptor = native_tls::TlsAcceptor::builder(identity.0.clone())
+            .min_protocol_version(settings.min_version.map(|_| Protocol::Tlsv12))
+            .max_protocol_version(
+                settings
+                    .max_version
+                    .filter(|v| *v == TlsVersion::Tls12)
+                    .map(|_| Protocol::Tlsv12),
+            )
+            .build()
+            .map_err(TlsError::InvalidIdentity)?;
+
+        Ok(Acceptor(acceptor.into()))
+    }
+
+    pub(crate) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> Result<TlsStream<S>, SessionError> {
+        Ok(self.0.accept(stream).await?)
+    }
+}
+
+pub(crate) fn validate(settings: &TlsSettings) -> Result<(), TlsError> {
+    if settings.cipher_suites.is_some() {
+        return Err(TlsError::Unsupported(
+            "choosing the cipher suites requires the rustls feature",
+        ));
+    }
+    if settings.client_ca.is_some() {
+        return Err(TlsError::Unsupported(
+            "client certificates require the rustls feature",
+        ));
+    }
+    if settings.min_version == Some(TlsVersion::Tls13) {
+        return Err(TlsError::Unsupported(
+            "requiring TLS 1.3 requires the rustls feature",
+        ));
+    }
+    Ok(())
+}
+
+/// native-tls does not tell what was negotiated.
+pub(crate) fn info<S>(_stream: &TlsStream<S>) -> TlsInfo {
+    TlsInfo::default()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    pub(crate) type ClientStream<S> = tokio_native_tls::TlsStream<S>;
+
+    /// Connect as a client trusting the test CA.
+    pub(crate) async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        domain: &str,
+        stream: S,
+        sni: bool,
+    ) -> ClientStream<S> {
+        let ca = native_tls::Certificate::from_pem(super::super::test::CA).unwrap();
+        let connector: tokio_native_tls::TlsConnector = native_tls::TlsConnector::builder()
+            .add_root_certificate(ca)
+            .disable_built_in_roots(true)
+            .use_sni(sni)
+            .build()
+            .unwrap()
+            .into();
+        connector.connect(domain, stream).await.unwrap()
+    }
+}
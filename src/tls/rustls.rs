@@ -0,0 +1,516 @@
+use super::{TlsSettings, TlsVersion};
+use crate::{
+    error::{SessionError, TlsError},
+    session::{ClientCertificate, TlsInfo},
+};
+use futures::ready;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth, ProtocolVersion, RootCertStore,
+    ServerConfig, ServerSession, Session, SupportedCipherSuite, ALL_CIPHERSUITES,
+};
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub use rustls::TLSError as Error;
+
+#[derive(Clone)]
+pub(crate) struct Identity {
+    chain: Vec<rustls::Certificate>,
+    private_key: rustls::PrivateKey,
+}
+
+impl Identity {
+    pub(crate) fn from_pkcs12(_der: &[u8], _password: &str) -> Result<Identity, TlsError> {
+        Err(TlsError::Unsupported(
+            "PKCS#12 archives require the native-tls feature",
+        ))
+    }
+
+    pub(crate) fn from_pem(chain: &[u8], private_key: &[u8]) -> Result<Identity, TlsError> {
+        use rustls::internal::pemfile;
+
+        let chain = pemfile::certs(&mut &chain[..])
+            .map_err(|()| TlsError::InvalidPem("invalid certificate chain".to_string()))?;
+        if chain.is_empty() {
+            return Err(TlsError::InvalidPem("no certificate found".to_string()));
+        }
+        let mut private_keys = pemfile::pkcs8_private_keys(&mut &private_key[..])
+            .map_err(|()| TlsError::InvalidPem("invalid private key".to_string()))?;
+        if private_keys.is_empty() {
+            private_keys = pemfile::rsa_private_keys(&mut &private_key[..])
+                .map_err(|()| TlsError::InvalidPem("invalid private key".to_string()))?;
+        }
+        let private_key = private_keys.into_iter().next().ok_or_else(|| {
+            TlsError::InvalidPem("no PKCS#8 or PKCS#1 private key found".to_string())
+        })?;
+        check_private_key(&chain[0], &private_key)?;
+
+        Ok(Identity { chain, private_key })
+    }
+}
+
+/// rustls does not check that the private key belongs to the certificate,
+/// so sign something with the key and verify it with the certificate.
+fn check_private_key(
+    certificate: &rustls::Certificate,
+    private_key: &rustls::PrivateKey,
+) -> Result<(), TlsError> {
+    use rustls::SignatureScheme::*;
+    const MESSAGE: &[u8] = b"smtp-server private key check";
+
+    let signing_key = rustls::sign::any_supported_type(private_key)
+        .map_err(|()| TlsError::InvalidPem("unsupported private key".to_string()))?;
+    let signer = signing_key
+        .choose_scheme(&[
+            RSA_PSS_SHA256,
+            ECDSA_NISTP256_SHA256,
+            ECDSA_NISTP384_SHA384,
+            ED25519,
+        ])
+        .ok_or_else(|| TlsError::InvalidPem("unsupported private key".to_string()))?;
+    let algorithm = match signer.get_scheme() {
+        RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        _ => &webpki::ED25519,
+    };
+    let signature = signer.sign(MESSAGE).map_err(TlsError::InvalidIdentity)?;
+
+    let certificate = webpki::EndEntityCert::from(&certificate.0)
+        .map_err(|e| TlsError::InvalidPem(format!("invalid certificate: {}", e)))?;
+    certificate
+        .verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|_| TlsError::KeyMismatch)
+}
+
+#[derive(Clone)]
+pub(crate) struct Acceptor(Arc<ServerConfig>);
+
+impl Acceptor {
+    pub(crate) fn new(identity: &Identity, settings: &TlsSettings) -> Result<Acceptor, TlsError> {
+        let client_verifier = match &settings.client_ca {
+            Some(pem) => AllowAnyAnonymousOrAuthenticatedClient::new(client_roots(pem)?),
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(client_verifier);
+        config.versions = versions(settings)?;
+        config.ciphersuites = cipher_suites(settings)?;
+        config
+            .set_single_cert(identity.chain.clone(), identity.private_key.clone())
+            .map_err(TlsError::InvalidIdentity)?;
+
+        Ok(Acceptor(Arc::new(config)))
+    }
+
+    pub(crate) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+    ) -> Result<TlsStream<S>, SessionError> {
+        let mut stream = TlsStream::new(stream, ServerSession::new(&self.0));
+        match stream.handshake().await {
+            Ok(()) => Ok(stream),
+            Err(e) => match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+                Some(e) => Err(SessionError::Tls(e.clone())),
+                None => Err(SessionError::Io(e)),
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for Acceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Acceptor")
+            .field("versions", &self.0.versions)
+            .field(
+                "cipher_suites",
+                &self
+                    .0
+                    .ciphersuites
+                    .iter()
+                    .map(|s| s.suite)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+pub(crate) fn validate(settings: &TlsSettings) -> Result<(), TlsError> {
+    versions(settings)?;
+    cipher_suites(settings)?;
+    if let Some(pem) = &settings.client_ca {
+        client_roots(pem)?;
+    }
+    Ok(())
+}
+
+fn client_roots(pem: &[u8]) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut &pem[..]) {
+        Ok((added, 0)) if added > 0 => Ok(roots),
+        _ => Err(TlsError::InvalidSettings(
+            "invalid CA certificates for the client certificates".to_string(),
+        )),
+    }
+}
+
+fn versions(settings: &TlsSettings) -> Result<Vec<ProtocolVersion>, TlsError> {
+    let versions: Vec<ProtocolVersion> = [
+        (TlsVersion::Tls13, ProtocolVersion::TLSv1_3),
+        (TlsVersion::Tls12, ProtocolVersion::TLSv1_2),
+    ]
+    .iter()
+    .filter(|(version, _)| {
+        settings
+            .min_version
+            .map(|min| *version >= min)
+            .unwrap_or(true)
+    })
+    .filter(|(version, _)| {
+        settings
+            .max_version
+            .map(|max| *version <= max)
+            .unwrap_or(true)
+    })
+    .map(|(_, version)| *version)
+    .collect();
+    if versions.is_empty() {
+        return Err(TlsError::InvalidSettings(
+            "no TLS version allowed".to_string(),
+        ));
+    }
+    Ok(versions)
+}
+
+fn cipher_suites(settings: &TlsSettings) -> Result<Vec<&'static SupportedCipherSuite>, TlsError> {
+    let names = match &settings.cipher_suites {
+        Some(names) => names,
+        None => return Ok(ALL_CIPHERSUITES.to_vec()),
+    };
+    let mut cipher_suites = Vec::new();
+    for name in names {
+        match ALL_CIPHERSUITES
+            .iter()
+            .find(|s| format!("{:?}", s.suite) == *name)
+        {
+            Some(cipher_suite) => cipher_suites.push(*cipher_suite),
+            None => {
+                return Err(TlsError::InvalidSettings(format!(
+                    "unknown cipher suite {}",
+                    name
+                )))
+            }
+        }
+    }
+
+    let versions = versions(settings)?;
+    if !cipher_suites
+        .iter()
+        .any(|s| versions.iter().any(|v| s.usable_for_version(*v)))
+    {
+        return Err(TlsError::InvalidSettings(
+            "no cipher suite usable with the allowed TLS versions".to_string(),
+        ));
+    }
+    Ok(cipher_suites)
+}
+
+pub(crate) fn info<S>(stream: &TlsStream<S>) -> TlsInfo {
+    TlsInfo {
+        protocol: stream
+            .session
+            .get_protocol_version()
+            .map(|version| match version {
+                ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+                ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+                version => format!("{:?}", version),
+            }),
+        cipher: stream
+            .session
+            .get_negotiated_ciphersuite()
+            .map(|s| format!("{:?}", s.suite)),
+        client_certificate: stream
+            .session
+            .get_peer_certificates()
+            .and_then(|chain| chain.into_iter().next())
+            .map(|certificate| {
+                let digest = ring::digest::digest(&ring::digest::SHA256, &certificate.0);
+                ClientCertificate {
+                    fingerprint: digest
+                        .as_ref()
+                        .iter()
+                        .map(|b| format!("{:02X}", b))
+                        .collect::<Vec<_>>()
+                        .join(":"),
+                    der: certificate.0,
+                }
+            }),
+    }
+}
+
+/// A TLS connection over a stream. rustls only works with blocking I/O, so calls to
+/// the stream are made through adapters turning `Poll::Pending` into `WouldBlock` errors.
+pub(crate) struct TlsStream<S, T = ServerSession> {
+    io: S,
+    session: T,
+    read_closed: bool,
+    write_closed: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin, T: Session + Unpin> TlsStream<S, T> {
+    pub(crate) fn new(io: S, session: T) -> TlsStream<S, T> {
+        TlsStream {
+            io,
+            session,
+            read_closed: false,
+            write_closed: false,
+        }
+    }
+
+    pub(crate) async fn handshake(&mut self) -> io::Result<()> {
+        futures::future::poll_fn(|cx| self.poll_handshake(cx)).await
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            while self.session.wants_write() {
+                ready!(self.write_io(cx))?;
+            }
+            if !self.session.is_handshaking() {
+                return Poll::Ready(Ok(()));
+            }
+            if ready!(self.read_io(cx))? == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed during the TLS handshake",
+                )));
+            }
+        }
+    }
+
+    /// Give the TLS records received to the session. Returns the number of bytes read, 0 at the end of the stream.
+    fn read_io(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let n = match self.session.read_tls(&mut Reader {
+            io: &mut self.io,
+            cx,
+        }) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+
+        if let Err(e) = self.session.process_new_packets() {
+            // try to tell the peer about the error
+            let _ = self.write_io(cx);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    /// Send the TLS records produced by the session.
+    fn write_io(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        match self.session.write_tls(&mut Writer {
+            io: &mut self.io,
+            cx,
+        }) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin, T: Session + Unpin> AsyncRead for TlsStream<S, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.read_closed || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            // the data already decrypted comes first
+            match this.session.read(buf.initialize_unfilled()) {
+                Ok(0) => (),
+                Ok(n) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                // the peer sent close_notify
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                    this.read_closed = true;
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            // read more records, and try again only if some were received
+            if ready!(this.read_io(cx))? == 0 {
+                this.read_closed = true;
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin, T: Session + Unpin> AsyncWrite for TlsStream<S, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let n = this.session.write(buf)?;
+        while this.session.wants_write() {
+            match this.write_io(cx) {
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                // the rest is sent when flushing
+                Poll::Pending if n > 0 => break,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        this.session.flush()?;
+        while this.session.wants_write() {
+            ready!(this.write_io(cx))?;
+        }
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.write_closed {
+            this.session.send_close_notify();
+            this.write_closed = true;
+        }
+        while this.session.wants_write() {
+            ready!(this.write_io(cx))?;
+        }
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+struct Reader<'a, 'b, S> {
+    io: &'a mut S,
+    cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, S: AsyncRead + Unpin> Read for Reader<'a, 'b, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+struct Writer<'a, 'b, S> {
+    io: &'a mut S,
+    cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, S: AsyncWrite + Unpin> Write for Writer<'a, 'b, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::TlsStream;
+    use rustls::{ClientConfig, ClientSession};
+    use std::sync::Arc;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    #[tokio::test]
+    #[cfg_attr(miri, ignore)]
+    async fn test_read() {
+        let identity =
+            super::Identity::from_pem(super::super::test::CHAIN, super::super::test::PRIVATE_KEY)
+                .unwrap();
+        let acceptor = super::Acceptor::new(&identity, &Default::default()).unwrap();
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            stream.write_all(b"220 ready\r\n").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        let mut client = connect("localhost", client, true).await;
+        server.await.unwrap();
+
+        // an empty buffer does not wait, even with decrypted data pending
+        assert_eq!(client.read(&mut []).await.unwrap(), 0);
+        let mut first = [0; 4];
+        client.read_exact(&mut first).await.unwrap();
+        assert_eq!(client.read(&mut []).await.unwrap(), 0);
+        let mut rest = String::new();
+        client.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "ready\r\n");
+    }
+
+    pub(crate) type ClientStream<S> = TlsStream<S, ClientSession>;
+
+    /// Connect as a client trusting the test CA.
+    pub(crate) async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        domain: &str,
+        stream: S,
+        sni: bool,
+    ) -> ClientStream<S> {
+        connect_with_config(domain, stream, sni, ClientConfig::new()).await
+    }
+
+    /// Connect as a client presenting the test client certificate.
+    pub(crate) async fn connect_with_certificate<S: AsyncRead + AsyncWrite + Unpin>(
+        domain: &str,
+        stream: S,
+    ) -> ClientStream<S> {
+        use rustls::internal::pemfile;
+
+        let chain = pemfile::certs(&mut &super::super::test::CLIENT_CHAIN[..]).unwrap();
+        let private_key =
+            pemfile::pkcs8_private_keys(&mut &super::super::test::CLIENT_PRIVATE_KEY[..])
+                .unwrap()
+                .remove(0);
+        let mut config = ClientConfig::new();
+        config.set_single_client_cert(chain, private_key).unwrap();
+        connect_with_config(domain, stream, true, config).await
+    }
+
+    async fn connect_with_config<S: AsyncRead + AsyncWrite + Unpin>(
+        domain: &str,
+        stream: S,
+        sni: bool,
+        mut config: ClientConfig,
+    ) -> ClientStream<S> {
+        config
+            .root_store
+            .add_pem_file(&mut &super::super::test::CA[..])
+            .unwrap();
+        config.enable_sni = sni;
+        let name = webpki::DNSNameRef::try_from_ascii_str(domain).unwrap();
+        let mut stream = TlsStream::new(stream, ClientSession::new(&Arc::new(config), name));
+        stream.handshake().await.unwrap();
+        stream
+    }
+}